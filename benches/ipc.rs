@@ -0,0 +1,69 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Compares the file IPC protocol with the ring buffer on the path an input
+// takes from the server to the game: one write of a small message followed by
+// a has_new check and a consume on the other side.
+//
+//     cargo bench --bench ipc
+
+use controlpads::{ipc, ring};
+use controlpads::ipc::IpcBackend;
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20_000;
+const MESSAGE: &[u8] = b"joystick:0.7071:-0.7071\0";
+
+fn report(label: &str, elapsed: Duration) {
+    let per_op = elapsed / ITERATIONS;
+    println!("{:<8} {:>10.2?} per write+consume ({} iterations, {:.2?} total)",
+             label, per_op, ITERATIONS, elapsed);
+}
+
+fn bench_files(name: &str) -> Duration {
+    ipc::initialize();
+    let files = ipc::FileBackend;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        files.write(name, MESSAGE).unwrap();
+        assert!(files.has_new(name).unwrap());
+        assert_eq!(files.consume(name).unwrap(), MESSAGE);
+    }
+    start.elapsed()
+}
+
+fn bench_ring(path: &str, name: &str) -> Duration {
+    let ring = ring::Ring::open(path, name, ring::DEFAULT_CAPACITY).unwrap();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        ring.write(MESSAGE).unwrap();
+        assert!(ring.has_new());
+        assert_eq!(ring.consume().unwrap(), MESSAGE);
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let name = format!("bench{}_in", std::process::id());
+    let ring_path = format!("{}/{}.ring", std::env::temp_dir().display(), name);
+    report("files", bench_files(&name));
+    report("ring", bench_ring(&ring_path, &name));
+    let _ = ipc::remove_file(&name);
+    let _ = std::fs::remove_file(&ring_path);
+}
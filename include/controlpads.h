@@ -0,0 +1,255 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#ifndef CONTROLPADS_H
+#define CONTROLPADS_H
+
+/* Generated by cbindgen from src/capi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define CP_OK 0
+
+#define CP_ERR_IO -1
+
+#define CP_ERR_CLIENT_NOT_FOUND -2
+
+#define CP_ERR_MALFORMED_DATA -3
+
+#define CP_ERR_SERVER_UNAVAILABLE -4
+
+#define CP_ERR_INVALID_ARGUMENT -5
+
+#define CP_ERR_INTERNAL -6
+
+typedef enum CPClientState {
+  CP_CLIENT_STATE_CONNECTED = 0,
+  CP_CLIENT_STATE_SUSPENDED = 1,
+} CPClientState;
+
+typedef enum CPMessageKind {
+  CP_MESSAGE_KIND_TEXT = 0,
+  CP_MESSAGE_KIND_BYTES = 1,
+} CPMessageKind;
+
+// A control pad client (see ClientHandle in the Rust API)
+typedef struct CPClient CPClient;
+
+// A list of client handles
+typedef struct CPClientList CPClientList;
+
+// A list of messages
+typedef struct CPMessageList CPMessageList;
+
+// A message borrowed from a CPMessageList. Text is utf8 and also NUL
+// terminated (but may contain NULs of its own, so trust len). received_us
+// and sent_us are when the server got the message and when the client
+// (probably) sent it, in microseconds since the Unix epoch.
+typedef struct CPMessage {
+  enum CPMessageKind kind;
+  const uint8_t *data;
+  size_t len;
+  uint64_t received_us;
+  uint64_t sent_us;
+} CPMessage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// A short description of a CP_* code. The string is static.
+const char *cp_error_string(int code);
+
+// Sets *changed to 1 if a client has been added, dropped, or refreshed since
+// the last call to cp_get_client_handles, 0 otherwise
+//
+// # Safety
+// changed must point to writable memory for an int
+int cp_clients_changed(int *changed);
+
+// Sets *list to a new list of the connected clients. Free it with
+// cp_client_list_free.
+//
+// # Safety
+// list must point to writable memory for a pointer
+int cp_get_client_handles(struct CPClientList **list);
+
+// The number of clients in list
+//
+// # Safety
+// list must be NULL or a list from cp_get_client_handles that hasn't been
+// freed
+size_t cp_client_list_len(const struct CPClientList *list);
+
+// The client at index, or NULL if index is out of range. The client belongs
+// to list.
+//
+// # Safety
+// list must be NULL or a list from cp_get_client_handles that hasn't been
+// freed
+const struct CPClient *cp_client_list_get(const struct CPClientList *list, size_t index);
+
+// Free a list from cp_get_client_handles
+//
+// # Safety
+// list must be NULL or a list from cp_get_client_handles that hasn't been
+// freed
+void cp_client_list_free(struct CPClientList *list);
+
+// A copy of client that the caller owns. Free it with cp_client_free.
+//
+// # Safety
+// client must be NULL or a valid client
+struct CPClient *cp_client_clone(const struct CPClient *client);
+
+// Free a client from cp_client_clone
+//
+// # Safety
+// client must be NULL or a client from cp_client_clone that hasn't been
+// freed
+void cp_client_free(struct CPClient *client);
+
+// 1 if a and b are the same client (even if its name or state changed
+// between the lists they came from), 0 otherwise
+//
+// # Safety
+// a and b must be NULL or valid clients
+int cp_client_same(const struct CPClient *a, const struct CPClient *b);
+
+// The client's display name (utf8). The string belongs to client.
+//
+// # Safety
+// client must be NULL or a valid client
+const char *cp_client_name(const struct CPClient *client);
+
+// A number unique to the client for as long as the server runs
+//
+// # Safety
+// client must be NULL or a valid client
+uint64_t cp_client_index(const struct CPClient *client);
+
+// When the client first connected in milliseconds since the unix epoch
+//
+// # Safety
+// client must be NULL or a valid client
+uint64_t cp_client_joined_ms(const struct CPClient *client);
+
+// Whether the client's phone was connected as of the list it came from
+//
+// # Safety
+// client must be NULL or a valid client
+enum CPClientState cp_client_state(const struct CPClient *client);
+
+// Sets *micros to the round trip time the server last measured to client in
+// microseconds, or -1 if it hasn't measured one yet
+//
+// # Safety
+// client must be a valid client and micros must point to writable memory
+// for an int64_t
+int cp_client_latency(const struct CPClient *client, int64_t *micros);
+
+// Send a NUL terminated utf8 text message to client
+//
+// # Safety
+// client must be a valid client and msg a NUL terminated string
+int cp_send_message(const struct CPClient *client, const char *msg);
+
+// Send len bytes from data to client as a binary message
+//
+// # Safety
+// client must be a valid client and data must point to len readable bytes
+// (or be NULL if len is 0)
+int cp_send_bytes(const struct CPClient *client, const uint8_t *data, size_t len);
+
+// Send a NUL terminated utf8 text message to every connected client
+//
+// # Safety
+// msg must be a NUL terminated string
+int cp_broadcast(const char *msg);
+
+// Send len bytes from data to every connected client as a binary message
+//
+// # Safety
+// data must point to len readable bytes (or be NULL if len is 0)
+int cp_broadcast_bytes(const uint8_t *data, size_t len);
+
+// Send a NUL terminated utf8 text message to each of the count clients in
+// clients
+//
+// # Safety
+// clients must point to count valid clients (or be NULL if count is 0) and
+// msg must be a NUL terminated string
+int cp_send_to(const struct CPClient *const *clients, size_t count, const char *msg);
+
+// Send len bytes from data to each of the count clients in clients as a
+// binary message
+//
+// # Safety
+// clients must point to count valid clients (or be NULL if count is 0) and
+// data must point to len readable bytes (or be NULL if len is 0)
+int cp_send_bytes_to(const struct CPClient *const *clients,
+                     size_t count,
+                     const uint8_t *data,
+                     size_t len);
+
+// Show every client the controller in bundle, a NUL terminated directory
+// path or URL, or go back to the server's default page if bundle is NULL
+// (see set_controller in the Rust API)
+//
+// # Safety
+// bundle must be NULL or a NUL terminated string
+int cp_set_controller(const char *bundle);
+
+// Sets *list to a new list of the messages received from client since the
+// last call for that client (possibly empty). Free it with
+// cp_message_list_free.
+//
+// # Safety
+// client must be a valid client and list must point to writable memory for
+// a pointer
+int cp_get_messages(const struct CPClient *client, struct CPMessageList **list);
+
+// The number of messages in list
+//
+// # Safety
+// list must be NULL or a list from cp_get_messages that hasn't been freed
+size_t cp_message_list_len(const struct CPMessageList *list);
+
+// Sets *msg to the message at index. msg->data belongs to list.
+//
+// # Safety
+// list must be NULL or a list from cp_get_messages that hasn't been freed
+// and msg must point to writable memory for a CPMessage
+int cp_message_list_get(const struct CPMessageList *list, size_t index, struct CPMessage *msg);
+
+// Free a list from cp_get_messages
+//
+// # Safety
+// list must be NULL or a list from cp_get_messages that hasn't been freed
+void cp_message_list_free(struct CPMessageList *list);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CONTROLPADS_H */
@@ -19,7 +19,7 @@
 
 pub const NUM_ANIMAL_NAMES: u64 = 100;
 
-pub const ANIMAL_NAMES: [&'static str; NUM_ANIMAL_NAMES as usize] = [
+pub const ANIMAL_NAMES: [&str; NUM_ANIMAL_NAMES as usize] = [
     "Alligator",
     "Bear",
     "Camel",
@@ -0,0 +1,273 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Condvar, Mutex, Once};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use futures_core::Stream;
+use crate::{ClientHandle, Event, Result};
+
+//=================================== Notes ====================================
+/*
+An async face on the functions in lib.rs (enabled by the "async" feature).
+
+Sending only writes an IPC object, so the async sends just do it on the
+calling thread. That still blocks the executor for as long as the write takes:
+waiting for the object's lock with Transport::Files, a round trip to the
+server with Transport::Socket (see ipc.rs). Both are normally well under a
+millisecond, but a game that can't afford them on its executor should hand
+the sends to something like tokio's spawn_blocking.
+
+Receiving is where games would otherwise block or poll: an EventStream
+registers its task's waker and one watcher thread per process sleeps in
+IpcBackend::wait_for_server(), waking every registered task whenever the
+server says something changed. Wakers are registered before checking for
+events, so a notification can't slip in between the check and the wait.
+
+The watcher shares the game's wake socket with wait_for_messages() and
+wait_for_clients_changed(), so don't mix those with EventStreams.
+*/
+
+//================================= Constants ==================================
+// how long the watcher sleeps before waking tasks anyway (for servers that
+// don't notify games)
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+//================================== Watcher ===================================
+static WAKERS: Mutex<Vec<Waker>> = Mutex::new(Vec::new());
+static WAKERS_ADDED: Condvar = Condvar::new();
+static WATCHER: Once = Once::new();
+
+fn register(waker: &Waker) {
+    WATCHER.call_once(|| {
+        std::thread::Builder::new().name("controlpads watcher".to_string())
+            .spawn(watch)
+            .expect("failed to start the controlpads watcher thread");
+    });
+    let mut wakers = WAKERS.lock().unwrap_or_else(|e| e.into_inner());
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+    WAKERS_ADDED.notify_one();
+}
+
+// the watcher thread: wake every waiting task each time the server notifies us
+fn watch() {
+    loop {
+        let mut wakers = WAKERS.lock().unwrap_or_else(|e| e.into_inner());
+        while wakers.is_empty() {
+            wakers = WAKERS_ADDED.wait(wakers).unwrap_or_else(|e| e.into_inner());
+        }
+        drop(wakers);
+        crate::backend().wait_for_server(RECHECK_INTERVAL);
+        let wakers = std::mem::take(&mut *WAKERS.lock().unwrap_or_else(|e| e.into_inner()));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+//================================ AsyncClient =================================
+/// Async access to the local control pad server.
+///
+/// The send methods do their IPC on the calling thread, so they block the
+/// executor briefly (usually well under a millisecond) instead of yielding.
+#[derive(Clone, Debug, Default)]
+pub struct AsyncClient {
+    _private: (),
+}
+
+impl AsyncClient {
+    pub fn new() -> Self {
+        AsyncClient { _private: () }
+    }
+
+    /// A stream of everything that happens to the control pad clients (see
+    /// poll_events, which it's built on). Errors don't end the stream. Only
+    /// use one stream at a time since each event goes to only one of them.
+    pub fn events(&self) -> EventStream {
+        EventStream { pending: VecDeque::new(), failed: false }
+    }
+
+    /// See send_message
+    pub async fn send_message(&self, client: &ClientHandle, msg: &str) -> Result<()> {
+        crate::send_message(client, msg)
+    }
+
+    /// See send_bytes
+    pub async fn send_bytes(&self, client: &ClientHandle, bytes: &[u8]) -> Result<()> {
+        crate::send_bytes(client, bytes)
+    }
+
+    /// See broadcast
+    pub async fn broadcast(&self, msg: &str) -> Result<()> {
+        crate::broadcast(msg)
+    }
+
+    /// See broadcast_bytes
+    pub async fn broadcast_bytes(&self, bytes: &[u8]) -> Result<()> {
+        crate::broadcast_bytes(bytes)
+    }
+
+    /// See send_to
+    pub async fn send_to(&self, clients: &[ClientHandle], msg: &str) -> Result<()> {
+        crate::send_to(clients, msg)
+    }
+
+    /// See send_bytes_to
+    pub async fn send_bytes_to(&self, clients: &[ClientHandle], bytes: &[u8]) -> Result<()> {
+        crate::send_bytes_to(clients, bytes)
+    }
+}
+
+//================================ EventStream =================================
+/// The events from AsyncClient::events. After an error the stream waits for
+/// the server (or the watcher's recheck) before trying again, so a caller
+/// that keeps polling through errors doesn't spin.
+#[derive(Debug)]
+pub struct EventStream {
+    // events from the last poll_events not handed out yet
+    pending: VecDeque<Event>,
+    // whether the last poll_events failed
+    failed: bool,
+}
+
+impl Stream for EventStream {
+    type Item = Result<Event>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Poll::Ready(Some(Ok(event)));
+        }
+        register(cx.waker());
+        if std::mem::take(&mut self.failed) {
+            return Poll::Pending;
+        }
+        match crate::poll_events() {
+            Err(e) => {
+                self.failed = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Ok(events) => {
+                self.pending.extend(events);
+                match self.pending.pop_front() {
+                    Some(event) => Poll::Ready(Some(Ok(event))),
+                    None => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use futures::executor::block_on;
+    use futures::task::ArcWake;
+    use futures::StreamExt;
+    use crate::{event, frame, ipc, ClientState, Message, Timing};
+    use crate::ipc::IpcBackend;
+
+    // tests that install a backend for the whole library take turns
+    static LIBRARY: Mutex<()> = Mutex::new(());
+
+    // play the server against an EventStream and an async send
+    #[test]
+    fn streams_events_and_sends() {
+        let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+        let memory = Arc::new(ipc::MemoryBackend::new());
+        crate::set_backend(memory.clone());
+        let phone = ClientHandle::new("phone-0", 0, "Zed", SystemTime::now(),
+                                      ClientState::Connected);
+        let timing = Timing::at(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let server = {
+            let memory = memory.clone();
+            let phone = phone.clone();
+            std::thread::spawn(move || {
+                // wait for the subscription then say something
+                let subscribe = frame::encode(&[Message::Text(event::SUBSCRIBE.to_string())]);
+                while memory.consume("rpc_out").unwrap() != subscribe {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                let events = [Event::Joined(phone.clone()),
+                              Event::Message(phone, Message::Text("hi game".into()), timing)];
+                for e in events {
+                    memory.write(event::EVENTS_OBJECT, &e.encode()).unwrap();
+                    memory.notify_game();
+                }
+            })
+        };
+        let client = AsyncClient::new();
+        let mut events = client.events();
+        block_on(async {
+            assert_eq!(events.next().await.unwrap().unwrap(), Event::Joined(phone.clone()));
+            assert_eq!(events.next().await.unwrap().unwrap(),
+                       Event::Message(phone.clone(), Message::Text("hi game".into()), timing));
+            client.send_message(&phone, "hello phone").await.unwrap();
+        });
+        server.join().unwrap();
+        assert_eq!(frame::decode(&memory.consume("phone-0_out").unwrap()).unwrap(),
+                   vec![Message::Text("hello phone".into())]);
+    }
+
+    // a stream that failed waits to be woken before it tries again
+    #[test]
+    fn waits_after_errors() {
+        struct Woken(AtomicBool);
+        impl ArcWake for Woken {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.store(true, Ordering::SeqCst);
+            }
+        }
+        let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+        let memory = Arc::new(ipc::MemoryBackend::new());
+        crate::set_backend(memory.clone());
+        memory.write(event::EVENTS_OBJECT, b"garbage").unwrap();
+        let woken = Arc::new(Woken(AtomicBool::new(false)));
+        let waker = futures::task::waker(woken.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut events = AsyncClient::new().events();
+        assert!(matches!(Pin::new(&mut events).poll_next(&mut cx),
+                         Poll::Ready(Some(Err(crate::ControlpadError::MalformedData(_))))));
+        assert!(Pin::new(&mut events).poll_next(&mut cx).is_pending());
+        let phone = ClientHandle::new("phone-0", 0, "Zed", SystemTime::now(),
+                                      ClientState::Connected);
+        memory.write(event::EVENTS_OBJECT, &Event::Joined(phone.clone()).encode()).unwrap();
+        memory.notify_game();
+        let start = std::time::Instant::now();
+        while !woken.0.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(5), "never woken");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        match Pin::new(&mut events).poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(event))) => assert_eq!(event, Event::Joined(phone)),
+            other => panic!("{:?}", other),
+        }
+    }
+}
+
+//==================================<===|===>===================================
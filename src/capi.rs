@@ -0,0 +1,541 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{ClientHandle, ClientState, ControlpadError, Message, Timing};
+
+//=================================== Notes ====================================
+/*
+The C interface to the controlpads library (see include/controlpads.h, which
+is generated from this file by cbindgen).
+
+Every function returns CP_OK or one of the negative CP_ERR_* codes and hands
+back anything else through out pointers. Ownership rules:
+  - Lists (CPClientList, CPMessageList) are owned by the caller and must be
+    freed with the matching *_free function. Passing NULL to a free is fine.
+  - Pointers returned by *_get functions borrow from their list and are only
+    valid until the list is freed. To keep a client handle around longer,
+    copy it with cp_client_clone and free the copy with cp_client_free.
+    A "valid client" below is one borrowed from a list that hasn't been
+    freed or one from cp_client_clone that hasn't been freed.
+  - Strings and buffers passed in are only read during the call.
+*/
+
+//================================= Constants ==================================
+pub const CP_OK: c_int = 0;
+pub const CP_ERR_IO: c_int = -1;
+pub const CP_ERR_CLIENT_NOT_FOUND: c_int = -2;
+pub const CP_ERR_MALFORMED_DATA: c_int = -3;
+pub const CP_ERR_SERVER_UNAVAILABLE: c_int = -4;
+pub const CP_ERR_INVALID_ARGUMENT: c_int = -5;
+// the library hit a bug; it's still safe to keep calling it
+pub const CP_ERR_INTERNAL: c_int = -6;
+
+//=================================== Types ====================================
+/// A control pad client (see ClientHandle in the Rust API)
+pub struct CPClient {
+    handle: ClientHandle,
+    // NUL terminated copy of the name
+    name: CString,
+}
+
+/// A list of client handles
+pub struct CPClientList {
+    clients: Vec<CPClient>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CPClientState {
+    Connected = 0,
+    Suspended = 1,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CPMessageKind {
+    Text = 0,
+    Bytes = 1,
+}
+
+/// A message borrowed from a CPMessageList. Text is utf8 and also NUL
+/// terminated (but may contain NULs of its own, so trust len). received_us
+/// and sent_us are when the server got the message and when the client
+/// (probably) sent it, in microseconds since the Unix epoch.
+#[repr(C)]
+pub struct CPMessage {
+    pub kind: CPMessageKind,
+    pub data: *const u8,
+    pub len: usize,
+    pub received_us: u64,
+    pub sent_us: u64,
+}
+
+/// A list of messages
+pub struct CPMessageList {
+    // (kind, data with a NUL after it, timing)
+    messages: Vec<(CPMessageKind, Vec<u8>, Timing)>,
+}
+
+//================================== Helpers ===================================
+fn epoch_micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or(0)
+}
+
+fn error_code(e: &ControlpadError) -> c_int {
+    match e {
+        ControlpadError::Io(_) => CP_ERR_IO,
+        ControlpadError::ClientNotFound(_) => CP_ERR_CLIENT_NOT_FOUND,
+        ControlpadError::MalformedData(_) => CP_ERR_MALFORMED_DATA,
+        ControlpadError::ServerUnavailable => CP_ERR_SERVER_UNAVAILABLE,
+    }
+}
+
+// run f, turning its errors (and panics, which mustn't cross into C) into
+// error codes
+fn guard(f: impl FnOnce() -> Result<(), c_int>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => CP_OK,
+        Ok(Err(code)) => code,
+        Err(_) => CP_ERR_INTERNAL,
+    }
+}
+
+// borrow a client argument
+unsafe fn client_arg<'a>(client: *const CPClient) -> Result<&'a ClientHandle, c_int> {
+    client.as_ref().map(|c| &c.handle).ok_or(CP_ERR_INVALID_ARGUMENT)
+}
+
+impl CPClient {
+    fn new(handle: ClientHandle) -> Self {
+        // names never have NULs in them but just in case
+        let name = CString::new(handle.name().replace('\0', ""))
+            .unwrap_or_default();
+        CPClient { handle, name }
+    }
+}
+
+fn nul_terminated(bytes: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(bytes.len() + 1);
+    v.extend_from_slice(bytes);
+    v.push(0);
+    v
+}
+
+//================================= Functions ==================================
+/// A short description of a CP_* code. The string is static.
+#[no_mangle]
+pub extern "C" fn cp_error_string(code: c_int) -> *const c_char {
+    let s: &'static CStr = match code {
+        CP_OK => c"ok",
+        CP_ERR_IO => c"IPC failed",
+        CP_ERR_CLIENT_NOT_FOUND => c"no such client",
+        CP_ERR_MALFORMED_DATA => c"malformed IPC data",
+        CP_ERR_SERVER_UNAVAILABLE => c"the controlpad server is not running",
+        CP_ERR_INVALID_ARGUMENT => c"invalid argument",
+        CP_ERR_INTERNAL => c"internal error",
+        _ => c"unknown error",
+    };
+    s.as_ptr()
+}
+
+/// Sets *changed to 1 if a client has been added, dropped, or refreshed since
+/// the last call to cp_get_client_handles, 0 otherwise
+///
+/// # Safety
+/// changed must point to writable memory for an int
+#[no_mangle]
+pub unsafe extern "C" fn cp_clients_changed(changed: *mut c_int) -> c_int {
+    if changed.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let c = crate::clients_changed().map_err(|e| error_code(&e))?;
+        *changed = c as c_int;
+        Ok(())
+    })
+}
+
+/// Sets *list to a new list of the connected clients. Free it with
+/// cp_client_list_free.
+///
+/// # Safety
+/// list must point to writable memory for a pointer
+#[no_mangle]
+pub unsafe extern "C" fn cp_get_client_handles(list: *mut *mut CPClientList) -> c_int {
+    if list.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let handles = crate::get_client_handles().map_err(|e| error_code(&e))?;
+        let clients = handles.into_iter().map(CPClient::new).collect();
+        *list = Box::into_raw(Box::new(CPClientList { clients }));
+        Ok(())
+    })
+}
+
+/// The number of clients in list
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_client_handles that hasn't been
+/// freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_list_len(list: *const CPClientList) -> usize {
+    list.as_ref().map(|l| l.clients.len()).unwrap_or(0)
+}
+
+/// The client at index, or NULL if index is out of range. The client belongs
+/// to list.
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_client_handles that hasn't been
+/// freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_list_get(list: *const CPClientList,
+                                            index: usize) -> *const CPClient {
+    match list.as_ref().and_then(|l| l.clients.get(index)) {
+        Some(client) => client,
+        None => std::ptr::null(),
+    }
+}
+
+/// Free a list from cp_get_client_handles
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_client_handles that hasn't been
+/// freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_list_free(list: *mut CPClientList) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+/// A copy of client that the caller owns. Free it with cp_client_free.
+///
+/// # Safety
+/// client must be NULL or a valid client
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_clone(client: *const CPClient) -> *mut CPClient {
+    match client.as_ref() {
+        Some(client) => Box::into_raw(Box::new(CPClient::new(client.handle.clone()))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Free a client from cp_client_clone
+///
+/// # Safety
+/// client must be NULL or a client from cp_client_clone that hasn't been
+/// freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_free(client: *mut CPClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// 1 if a and b are the same client (even if its name or state changed
+/// between the lists they came from), 0 otherwise
+///
+/// # Safety
+/// a and b must be NULL or valid clients
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_same(a: *const CPClient, b: *const CPClient) -> c_int {
+    match (a.as_ref(), b.as_ref()) {
+        (Some(a), Some(b)) => (a.handle == b.handle) as c_int,
+        _ => 0,
+    }
+}
+
+/// The client's display name (utf8). The string belongs to client.
+///
+/// # Safety
+/// client must be NULL or a valid client
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_name(client: *const CPClient) -> *const c_char {
+    match client.as_ref() {
+        Some(client) => client.name.as_ptr(),
+        None => c"".as_ptr(),
+    }
+}
+
+/// A number unique to the client for as long as the server runs
+///
+/// # Safety
+/// client must be NULL or a valid client
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_index(client: *const CPClient) -> u64 {
+    client.as_ref().map(|c| c.handle.index()).unwrap_or(0)
+}
+
+/// When the client first connected in milliseconds since the unix epoch
+///
+/// # Safety
+/// client must be NULL or a valid client
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_joined_ms(client: *const CPClient) -> u64 {
+    client.as_ref()
+        .and_then(|c| c.handle.joined_at().duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Whether the client's phone was connected as of the list it came from
+///
+/// # Safety
+/// client must be NULL or a valid client
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_state(client: *const CPClient) -> CPClientState {
+    match client.as_ref().map(|c| c.handle.state()) {
+        Some(ClientState::Connected) => CPClientState::Connected,
+        _ => CPClientState::Suspended,
+    }
+}
+
+/// Sets *micros to the round trip time the server last measured to client in
+/// microseconds, or -1 if it hasn't measured one yet
+///
+/// # Safety
+/// client must be a valid client and micros must point to writable memory
+/// for an int64_t
+#[no_mangle]
+pub unsafe extern "C" fn cp_client_latency(client: *const CPClient, micros: *mut i64) -> c_int {
+    if micros.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let client = client_arg(client)?;
+        let latency = crate::latency(client).map_err(|e| error_code(&e))?;
+        *micros = latency.map(|rtt| rtt.as_micros() as i64).unwrap_or(-1);
+        Ok(())
+    })
+}
+
+/// Send a NUL terminated utf8 text message to client
+///
+/// # Safety
+/// client must be a valid client and msg a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cp_send_message(client: *const CPClient,
+                                         msg: *const c_char) -> c_int {
+    if msg.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let client = client_arg(client)?;
+        let msg = CStr::from_ptr(msg).to_str().map_err(|_| CP_ERR_INVALID_ARGUMENT)?;
+        crate::send_message(client, msg).map_err(|e| error_code(&e))
+    })
+}
+
+/// Send len bytes from data to client as a binary message
+///
+/// # Safety
+/// client must be a valid client and data must point to len readable bytes
+/// (or be NULL if len is 0)
+#[no_mangle]
+pub unsafe extern "C" fn cp_send_bytes(client: *const CPClient, data: *const u8,
+                                       len: usize) -> c_int {
+    if data.is_null() && len != 0 {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let client = client_arg(client)?;
+        let bytes = if len == 0 { &[][..] } else { std::slice::from_raw_parts(data, len) };
+        crate::send_bytes(client, bytes).map_err(|e| error_code(&e))
+    })
+}
+
+/// Send a NUL terminated utf8 text message to every connected client
+///
+/// # Safety
+/// msg must be a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cp_broadcast(msg: *const c_char) -> c_int {
+    if msg.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let msg = CStr::from_ptr(msg).to_str().map_err(|_| CP_ERR_INVALID_ARGUMENT)?;
+        crate::broadcast(msg).map_err(|e| error_code(&e))
+    })
+}
+
+/// Send len bytes from data to every connected client as a binary message
+///
+/// # Safety
+/// data must point to len readable bytes (or be NULL if len is 0)
+#[no_mangle]
+pub unsafe extern "C" fn cp_broadcast_bytes(data: *const u8, len: usize) -> c_int {
+    if data.is_null() && len != 0 {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let bytes = if len == 0 { &[][..] } else { std::slice::from_raw_parts(data, len) };
+        crate::broadcast_bytes(bytes).map_err(|e| error_code(&e))
+    })
+}
+
+// the handles for an array of count clients
+unsafe fn clients_arg(clients: *const *const CPClient,
+                      count: usize) -> Result<Vec<ClientHandle>, c_int> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    if clients.is_null() {
+        return Err(CP_ERR_INVALID_ARGUMENT);
+    }
+    std::slice::from_raw_parts(clients, count).iter()
+        .map(|&c| client_arg(c).cloned())
+        .collect()
+}
+
+/// Send a NUL terminated utf8 text message to each of the count clients in
+/// clients
+///
+/// # Safety
+/// clients must point to count valid clients (or be NULL if count is 0) and
+/// msg must be a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cp_send_to(clients: *const *const CPClient, count: usize,
+                                    msg: *const c_char) -> c_int {
+    if msg.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let clients = clients_arg(clients, count)?;
+        let msg = CStr::from_ptr(msg).to_str().map_err(|_| CP_ERR_INVALID_ARGUMENT)?;
+        crate::send_to(&clients, msg).map_err(|e| error_code(&e))
+    })
+}
+
+/// Send len bytes from data to each of the count clients in clients as a
+/// binary message
+///
+/// # Safety
+/// clients must point to count valid clients (or be NULL if count is 0) and
+/// data must point to len readable bytes (or be NULL if len is 0)
+#[no_mangle]
+pub unsafe extern "C" fn cp_send_bytes_to(clients: *const *const CPClient, count: usize,
+                                          data: *const u8, len: usize) -> c_int {
+    if data.is_null() && len != 0 {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let clients = clients_arg(clients, count)?;
+        let bytes = if len == 0 { &[][..] } else { std::slice::from_raw_parts(data, len) };
+        crate::send_bytes_to(&clients, bytes).map_err(|e| error_code(&e))
+    })
+}
+
+/// Show every client the controller in bundle, a NUL terminated directory
+/// path or URL, or go back to the server's default page if bundle is NULL
+/// (see set_controller in the Rust API)
+///
+/// # Safety
+/// bundle must be NULL or a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn cp_set_controller(bundle: *const c_char) -> c_int {
+    guard(|| {
+        let bundle = if bundle.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(bundle).to_str().map_err(|_| CP_ERR_INVALID_ARGUMENT)?)
+        };
+        crate::set_controller(bundle).map_err(|e| error_code(&e))
+    })
+}
+
+/// Sets *list to a new list of the messages received from client since the
+/// last call for that client (possibly empty). Free it with
+/// cp_message_list_free.
+///
+/// # Safety
+/// client must be a valid client and list must point to writable memory for
+/// a pointer
+#[no_mangle]
+pub unsafe extern "C" fn cp_get_messages(client: *const CPClient,
+                                         list: *mut *mut CPMessageList) -> c_int {
+    if list.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    guard(|| {
+        let client = client_arg(client)?;
+        let msgs = crate::get_timed_messages(client).map_err(|e| error_code(&e))?;
+        let messages = msgs.into_iter().map(|(m, timing)| match m {
+            Message::Text(s) => (CPMessageKind::Text, nul_terminated(s.as_bytes()), timing),
+            Message::Bytes(b) => (CPMessageKind::Bytes, nul_terminated(&b), timing),
+        }).collect();
+        *list = Box::into_raw(Box::new(CPMessageList { messages }));
+        Ok(())
+    })
+}
+
+/// The number of messages in list
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_messages that hasn't been freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_message_list_len(list: *const CPMessageList) -> usize {
+    list.as_ref().map(|l| l.messages.len()).unwrap_or(0)
+}
+
+/// Sets *msg to the message at index. msg->data belongs to list.
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_messages that hasn't been freed
+/// and msg must point to writable memory for a CPMessage
+#[no_mangle]
+pub unsafe extern "C" fn cp_message_list_get(list: *const CPMessageList, index: usize,
+                                             msg: *mut CPMessage) -> c_int {
+    if msg.is_null() {
+        return CP_ERR_INVALID_ARGUMENT;
+    }
+    match list.as_ref().and_then(|l| l.messages.get(index)) {
+        Some((kind, data, timing)) => {
+            *msg = CPMessage {
+                kind: *kind,
+                data: data.as_ptr(),
+                len: data.len() - 1,
+                received_us: epoch_micros(timing.received_at),
+                sent_us: epoch_micros(timing.sent_at),
+            };
+            CP_OK
+        }
+        None => CP_ERR_INVALID_ARGUMENT,
+    }
+}
+
+/// Free a list from cp_get_messages
+///
+/// # Safety
+/// list must be NULL or a list from cp_get_messages that hasn't been freed
+#[no_mangle]
+pub unsafe extern "C" fn cp_message_list_free(list: *mut CPMessageList) {
+    if !list.is_null() {
+        drop(Box::from_raw(list));
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,201 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::ipc::Result;
+
+//=================================== Notes ====================================
+/*
+cp_clients holds one Bytes frame (see frame.rs) per connected client, and the
+payload of each is a record:
+
+[index: u64 LE][joined: u64 LE ms since the unix epoch][state: u8]
+[id len: u16 LE][id][name len: u16 LE][name]
+
+The server rewrites the whole object whenever a client joins, leaves, is
+suspended or resumed, or changes its name.
+*/
+
+//================================ ClientState =================================
+/// Whether a client's phone is currently connected
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientState {
+    Connected,
+    /// The phone disconnected and the server is holding on to the client
+    /// (and any messages for it) in case it comes back
+    Suspended,
+}
+
+impl ClientState {
+    fn to_byte(self) -> u8 {
+        match self {
+            ClientState::Connected => 0,
+            ClientState::Suspended => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            0 => Ok(ClientState::Connected),
+            1 => Ok(ClientState::Suspended),
+            _ => Err(format!("unknown client state {}", b).into()),
+        }
+    }
+}
+
+//================================ ClientHandle ================================
+/// A control pad client as of the call to get_client_handles that returned
+/// it. Two handles are equal if they refer to the same client, even if its
+/// name or state changed in between.
+#[derive(Clone, Debug)]
+pub struct ClientHandle {
+    id: String,
+    index: u64,
+    name: String,
+    joined: SystemTime,
+    state: ClientState,
+}
+
+impl ClientHandle {
+    #[doc(hidden)]
+    pub fn new(id: &str, index: u64, name: &str, joined: SystemTime,
+               state: ClientState) -> Self {
+        ClientHandle {
+            id: id.to_string(),
+            index,
+            name: name.to_string(),
+            joined,
+            state,
+        }
+    }
+
+    /// The name the player chose (or was assigned)
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// When the client first connected
+    pub fn joined_at(&self) -> SystemTime {
+        self.joined
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    /// A number unique to this client for as long as the server runs. Clients
+    /// that join later get larger numbers.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    // the server's id for the client, which names its IPC objects
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    #[doc(hidden)]
+    pub fn to_record(&self) -> Vec<u8> {
+        let joined_ms = self.joined.duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO).as_millis() as u64;
+        let mut out = Vec::with_capacity(21 + self.id.len() + self.name.len());
+        out.extend_from_slice(&self.index.to_le_bytes());
+        out.extend_from_slice(&joined_ms.to_le_bytes());
+        out.push(self.state.to_byte());
+        for s in [&self.id, &self.name] {
+            out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        out
+    }
+
+    #[doc(hidden)]
+    pub fn from_record(record: &[u8]) -> Result<Self> {
+        let too_short = || format!("client record of {} bytes is too short", record.len());
+        let fixed = record.get(..17).ok_or_else(too_short)?;
+        // unwraps because the slices are the right length
+        let index = u64::from_le_bytes(fixed[0..8].try_into().unwrap());
+        let joined_ms = u64::from_le_bytes(fixed[8..16].try_into().unwrap());
+        let state = ClientState::from_byte(fixed[16])?;
+        let mut rest = &record[17..];
+        let mut strings = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let len_bytes = rest.get(..2).ok_or_else(too_short)?;
+            let len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+            let s = rest.get(2..2 + len).ok_or_else(too_short)?;
+            strings.push(String::from_utf8(s.to_vec())?);
+            rest = &rest[2 + len..];
+        }
+        let name = strings.pop().unwrap_or_default();
+        let id = strings.pop().unwrap_or_default();
+        Ok(ClientHandle {
+            id,
+            index,
+            name,
+            joined: UNIX_EPOCH + Duration::from_millis(joined_ms),
+            state,
+        })
+    }
+}
+
+impl PartialEq for ClientHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for ClientHandle {}
+
+impl Hash for ClientHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+impl fmt::Display for ClientHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (#{})", self.name, self.index)
+    }
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trips() {
+        let joined = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let handle = ClientHandle::new("0a9574c70a06d95f-3", 7, "Ünïcode Otter",
+                                       joined, ClientState::Suspended);
+        let decoded = ClientHandle::from_record(&handle.to_record()).unwrap();
+        assert_eq!(decoded.id(), handle.id());
+        assert_eq!(decoded.index(), 7);
+        assert_eq!(decoded.name(), "Ünïcode Otter");
+        assert_eq!(decoded.joined_at(), joined);
+        assert_eq!(decoded.state(), ClientState::Suspended);
+        assert!(ClientHandle::from_record(&handle.to_record()[..20]).is_err());
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,161 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+//=================================== Notes ====================================
+/*
+Clock sync between the server and a client is NTP's exchange in both
+directions, with times as milliseconds since the Unix epoch (fractions
+allowed, e.g. performance.timeOrigin + performance.now() in a browser):
+
+  _ping:<t0>             t0: when the sender sent the ping, by its clock
+  _pong:<t0>:<t1>:<t2>   t0 echoed, t1/t2: when the ping arrived and when
+                         the pong was sent, by the replier's clock
+
+The sender notes t3 when the pong arrives and then
+
+  offset = ((t1 - t0) + (t2 - t3)) / 2    (replier's clock - sender's clock)
+  delay  = (t3 - t0) - (t2 - t1)          (round trip on the network)
+
+The samples with the least delay are the least skewed by the network, so the
+offset is taken from the best of the last few.
+
+A page opts in by pinging the server (which answers with a pong and, from then
+on, pings the page too so that it learns the page's clock) or by stamping a
+message. Stamping means sending
+
+  _at:<t>                t: when the next message was sent, by the page's clock
+
+just before a game message, which lets the server tell the game when the
+message was sent rather than guessing from the round trip time.
+*/
+
+//================================= Constants ==================================
+pub const PING: &str = "_ping";
+pub const PONG: &str = "_pong";
+pub const AT: &str = "_at";
+//
+// how many samples the offset is picked from
+const MAX_SAMPLES: usize = 8;
+
+//================================== Clocks ====================================
+/* Milliseconds since the Unix epoch, the unit of the clock messages.
+ */
+pub fn to_millis(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64() * 1000.0
+}
+
+/* The time *millis* since the Unix epoch, None if that isn't a time.
+ */
+pub fn from_millis(millis: f64) -> Option<SystemTime> {
+    if !millis.is_finite() || millis < 0.0 {
+        return None;
+    }
+    UNIX_EPOCH.checked_add(Duration::try_from_secs_f64(millis / 1000.0).ok()?)
+}
+
+/* A time in a clock message.
+ */
+pub fn parse_millis(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter(|t| t.is_finite())
+}
+
+//================================= ClockSync ==================================
+/* Estimates how far another clock is from ours from ping/pong exchanges.
+ */
+#[derive(Default)]
+pub struct ClockSync {
+    // (offset, delay) of the latest exchanges, in ms
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Add the exchange of a ping we sent at *t0* and whose pong came back at
+     * *t3*, answered at *t1* and *t2* by the other clock.
+     */
+    pub fn add_sample(&mut self, t0: f64, t1: f64, t2: f64, t3: f64) {
+        let offset = ((t1 - t0) + (t2 - t3)) / 2.0;
+        // clocks with coarse resolution can make a fast exchange look like
+        // it took less than no time
+        let delay = ((t3 - t0) - (t2 - t1)).max(0.0);
+        if !offset.is_finite() || !delay.is_finite() {
+            return;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((offset, delay));
+    }
+
+    /* The other clock minus ours in ms, None before the first exchange.
+     */
+    pub fn offset(&self) -> Option<f64> {
+        self.samples.iter()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|&(offset, _)| offset)
+    }
+
+    /* The time *millis* on the other clock by ours.
+     */
+    pub fn to_local(&self, millis: f64) -> Option<SystemTime> {
+        from_millis(millis - self.offset()?)
+    }
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_offset_of_the_fastest_exchange() {
+        let mut clock = ClockSync::new();
+        assert_eq!(clock.offset(), None);
+        // the other clock is 500ms ahead; 10ms each way
+        clock.add_sample(1000.0, 1510.0, 1511.0, 1021.0);
+        assert_eq!(clock.offset(), Some(500.0));
+        // a slow, lopsided exchange (50ms out, 2ms back) is off by 24ms
+        clock.add_sample(2000.0, 2550.0, 2550.0, 2052.0);
+        assert_eq!(clock.offset(), Some(500.0));
+        // and a faster one wins
+        clock.add_sample(3000.0, 3501.0, 3501.0, 3002.0);
+        assert_eq!(clock.offset(), Some(500.0));
+        assert_eq!(clock.to_local(1_500.0), from_millis(1_000.0));
+    }
+
+    #[test]
+    fn millis_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let back = from_millis(to_millis(time)).unwrap();
+        let error = back.duration_since(time).unwrap_or_else(|e| e.duration());
+        assert!(error < Duration::from_micros(1));
+        assert_eq!(from_millis(-1.0), None);
+        assert_eq!(parse_millis("NaN"), None);
+        assert_eq!(parse_millis("12.5"), Some(12.5));
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,186 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::path::{PathBuf, MAIN_SEPARATOR};
+use std::sync::OnceLock;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+//=================================== Notes ====================================
+/*
+Where a server and the games talking to it keep their IPC objects and lock
+files. Everything a server creates lives in <ipc dir>/<namespace>/ and
+<lock dir>/sl_<namespace>.*, so servers with different namespaces (or
+different directories) can run side by side without seeing each other's
+clients. With no namespace the layout is the same as it has always been.
+
+Games pick these up from the environment. The server also takes them on the
+command line (--ipc-dir, --lock-dir, --namespace).
+*/
+
+//================================= Constants ==================================
+#[cfg(target_os = "macos")]
+const DEFAULT_IPC_DIR: &str = "/var/tmp/";
+#[cfg(target_os = "linux")]
+//const DEFAULT_IPC_DIR: &str = "/home/requin/ipc/";
+const DEFAULT_IPC_DIR: &str = "/dev/shm/rqnio/";
+#[cfg(target_os = "windows")]
+const DEFAULT_IPC_DIR: &str = "C:\\Users\\gamenite\\";
+//
+#[cfg(target_os = "macos")]
+const DEFAULT_LOCK_DIR: &str = "/var/tmp";
+#[cfg(target_os = "linux")]
+//const DEFAULT_LOCK_DIR: &str = "/var/lock/";
+const DEFAULT_LOCK_DIR: &str = "/dev/shm/";
+#[cfg(target_os = "windows")]
+const DEFAULT_LOCK_DIR: &str = "C:\\Users\\gamenite";
+//
+const LOCK_PREFIX: &str = "sl_";
+// between the namespace and the name in a lock file name; validate() keeps
+// it out of namespaces so that no two (namespace, name) pairs share a lock
+const LOCK_SEPARATOR: char = '.';
+//
+pub const IPC_DIR_VAR: &str = "CONTROLPAD_IPC_DIR";
+pub const LOCK_DIR_VAR: &str = "CONTROLPAD_LOCK_DIR";
+pub const NAMESPACE_VAR: &str = "CONTROLPAD_NAMESPACE";
+
+//==================================== Dirs ====================================
+#[derive(Clone, Debug)]
+pub struct Dirs {
+    pub ipc_dir: String,
+    pub lock_dir: String,
+    pub namespace: String,
+}
+
+impl Dirs {
+    /* The defaults overridden by whichever of CONTROLPAD_IPC_DIR,
+     * CONTROLPAD_LOCK_DIR and CONTROLPAD_NAMESPACE are set.
+     */
+    pub fn from_env() -> Self {
+        let var_or = |var: &str, default: &str| {
+            std::env::var(var).unwrap_or_else(|_| default.to_string())
+        };
+        Dirs {
+            ipc_dir: var_or(IPC_DIR_VAR, DEFAULT_IPC_DIR),
+            lock_dir: var_or(LOCK_DIR_VAR, DEFAULT_LOCK_DIR),
+            namespace: var_or(NAMESPACE_VAR, ""),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let ok = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if !self.namespace.chars().all(ok) {
+            return Err(format!("invalid namespace '{}': only letters, digits, \
+                                '-' and '_' are allowed", self.namespace).into());
+        }
+        Ok(())
+    }
+
+    /* The directory holding this namespace's IPC objects, ending in a path
+     * separator so object names can be appended directly.
+     */
+    pub fn ipc_path(&self) -> String {
+        let mut path = PathBuf::from(&self.ipc_dir);
+        if !self.namespace.is_empty() {
+            path.push(&self.namespace);
+        }
+        let mut s = path.to_string_lossy().into_owned();
+        if !s.ends_with(MAIN_SEPARATOR) {
+            s.push(MAIN_SEPARATOR);
+        }
+        s
+    }
+
+    /* The lock file guarding the IPC object (or other resource) *name*.
+     */
+    pub fn lock_path(&self, name: &str) -> String {
+        let file_name = if self.namespace.is_empty() {
+            format!("{}{}", LOCK_PREFIX, name)
+        } else {
+            format!("{}{}{}{}", LOCK_PREFIX, self.namespace, LOCK_SEPARATOR, name)
+        };
+        PathBuf::from(&self.lock_dir).join(file_name).to_string_lossy().into_owned()
+    }
+}
+
+// the error is kept as text so that every caller can have a copy of it
+static DIRS: OnceLock<std::result::Result<Dirs, String>> = OnceLock::new();
+
+/* Use *dirs* for everything in this process. Must be called before any IPC
+ * happens.
+ */
+pub fn set_dirs(dirs: Dirs) -> Result<()> {
+    dirs.validate()?;
+    DIRS.set(Ok(dirs)).map_err(|_| "directories were already in use".into())
+}
+
+/* The directories in use, from the environment unless set_dirs() was called
+ * first. An error if the environment names an invalid namespace.
+ */
+pub fn dirs() -> Result<&'static Dirs> {
+    DIRS.get_or_init(|| {
+        let dirs = Dirs::from_env();
+        dirs.validate().map(|_| dirs).map_err(|e| e.to_string())
+    }).as_ref().map_err(|e| e.clone().into())
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirs(namespace: &str) -> Dirs {
+        Dirs {
+            ipc_dir: format!("{}ipc", MAIN_SEPARATOR),
+            lock_dir: format!("{}locks", MAIN_SEPARATOR),
+            namespace: namespace.to_string(),
+        }
+    }
+
+    #[test]
+    fn validates_namespaces() {
+        for ok in ["", "console", "test-2", "a_b"] {
+            assert!(dirs(ok).validate().is_ok(), "{}", ok);
+        }
+        for bad in ["../up", "a/b", "a.b", "a b", "ünï"] {
+            assert!(dirs(bad).validate().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn ipc_path_ends_in_a_separator() {
+        let sep = MAIN_SEPARATOR;
+        assert_eq!(dirs("").ipc_path(), format!("{}ipc{}", sep, sep));
+        assert_eq!(dirs("test").ipc_path(), format!("{}ipc{}test{}", sep, sep, sep));
+        let trailing = Dirs { ipc_dir: format!("{}ipc{}", sep, sep), ..dirs("") };
+        assert_eq!(trailing.ipc_path(), dirs("").ipc_path());
+    }
+
+    #[test]
+    fn lock_paths_dont_collide_across_namespaces() {
+        let sep = MAIN_SEPARATOR;
+        assert_eq!(dirs("").lock_path("cp_clients"), format!("{}locks{}sl_cp_clients", sep, sep));
+        assert_eq!(dirs("test").lock_path("cp_clients"),
+                   format!("{}locks{}sl_test.cp_clients", sep, sep));
+        assert_ne!(dirs("a_b").lock_path("c"), dirs("a").lock_path("b_c"));
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,54 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+//=================================== Notes ====================================
+/*
+Each game can bring its own controller UI: a bundle that's either a directory
+of files (an index.html and whatever it loads) or the URL of a page hosted
+somewhere else. The game registers it by writing "set_controller:<bundle>" to
+rpc_out, with directories given as absolute paths and an empty bundle going
+back to the server's default page. A "reload" (the console switching games)
+also goes back to the default page.
+
+The server serves a directory bundle under /controller/<n>/ on the port phones
+connect to, with n changing every time so browsers never mix up two games'
+files, and tells every client "_controller:<url>" (an empty url meaning the
+default page) when the bundle changes and when the client connects. The
+default page (src/index.html) swaps directory bundles into an iframe over its
+own pad without reloading, so the phone stays connected, and takes the empty
+url as the cue to drop the iframe again. A URL bundle can only be shown by
+loading that page instead, so for those the phone does a full load.
+*/
+
+//================================= Constants ==================================
+pub const SET_CONTROLLER: &str = "set_controller:";
+// the message telling clients which controller to show
+pub const CONTROLLER_MSG: &str = "_controller:";
+// where directory bundles are served from
+pub const MOUNT_PREFIX: &str = "/controller/";
+
+//================================= Controller =================================
+/* Whether *bundle* is a URL (rather than a directory).
+ */
+pub fn is_url(bundle: &str) -> bool {
+    bundle.starts_with("http://") || bundle.starts_with("https://")
+}
+
+//==================================<===|===>===================================
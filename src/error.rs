@@ -0,0 +1,65 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::fmt;
+
+use crate::ipc;
+use crate::ClientHandle;
+
+//============================== ControlpadError ===============================
+/// Everything that can go wrong talking to the control pad server
+#[derive(Debug)]
+pub enum ControlpadError {
+    /// Reading or writing an IPC object failed
+    Io(ipc::Error),
+    /// The client isn't one of the clients from the latest call to
+    /// get_client_handles (it probably disconnected)
+    ClientNotFound(ClientHandle),
+    /// An IPC object held something that couldn't be decoded
+    MalformedData(String),
+    /// No control pad server is running for our IPC namespace
+    ServerUnavailable,
+}
+
+impl fmt::Display for ControlpadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlpadError::Io(e) => write!(f, "IPC failed: {}", e),
+            ControlpadError::ClientNotFound(client) => {
+                write!(f, "no connected client {}", client)
+            }
+            ControlpadError::MalformedData(e) => write!(f, "malformed IPC data: {}", e),
+            ControlpadError::ServerUnavailable => {
+                write!(f, "the controlpad server is not running")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ControlpadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ControlpadError::Io(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+//==================================<===|===>===================================
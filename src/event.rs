@@ -0,0 +1,213 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use crate::client::ClientHandle;
+use crate::frame::{self, Message, Timing};
+use crate::ipc::Result;
+use std::time::SystemTime;
+
+//=================================== Notes ====================================
+/*
+A game subscribes to events by writing "subscribe_events" to rpc_out. From
+then on the server writes to the events IPC object instead of telling the game
+about clients through cp_clients changes and in-band messages:
+  - a Joined for every client that's already connected,
+  - then every Joined/Left/Renamed/Suspended/Reconnected as it happens,
+  - and every message from a client (which no longer goes to <id>_in).
+The subscription ends when rpc_out gets "unsubscribe_events" or "reload" (the
+console switching games), and the server says so with an Unsubscribed event
+so that the library knows to subscribe again.
+
+Each event is a Bytes frame (see frame.rs) holding:
+
+[kind: u8][record len: u32 LE][client record (see client.rs)][extra]
+
+where extra is the old name for Renamed, the message's timing frame and frame
+for Message and nothing otherwise. Unsubscribed has no record (record len 0).
+*/
+
+//================================= Constants ==================================
+const KIND_JOINED: u8 = 1;
+const KIND_LEFT: u8 = 2;
+const KIND_RENAMED: u8 = 3;
+const KIND_SUSPENDED: u8 = 4;
+const KIND_RECONNECTED: u8 = 5;
+const KIND_MESSAGE: u8 = 6;
+const KIND_UNSUBSCRIBED: u8 = 0xFF;
+//
+pub const EVENTS_OBJECT: &str = "events";
+pub const SUBSCRIBE: &str = "subscribe_events";
+pub const UNSUBSCRIBE: &str = "unsubscribe_events";
+
+//=================================== Event ====================================
+/// Something that happened to a control pad client. The ClientHandle in each
+/// event describes the client as of that event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Joined(ClientHandle),
+    /// The client is gone for good
+    Left(ClientHandle),
+    Renamed { client: ClientHandle, old_name: String },
+    /// The client's phone disconnected and the server is waiting for it to
+    /// come back (ClientState::Suspended)
+    Suspended(ClientHandle),
+    /// A suspended client came back
+    Reconnected(ClientHandle),
+    /// A message from the client, with when it was sent and received
+    Message(ClientHandle, Message, Timing),
+    #[doc(hidden)]
+    Unsubscribed,
+}
+
+impl Event {
+    #[doc(hidden)]
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, client, extra) = match self {
+            Event::Joined(c) => (KIND_JOINED, Some(c), vec![]),
+            Event::Left(c) => (KIND_LEFT, Some(c), vec![]),
+            Event::Renamed { client, old_name } => {
+                (KIND_RENAMED, Some(client), old_name.as_bytes().to_vec())
+            }
+            Event::Suspended(c) => (KIND_SUSPENDED, Some(c), vec![]),
+            Event::Reconnected(c) => (KIND_RECONNECTED, Some(c), vec![]),
+            Event::Message(c, msg, timing) => {
+                (KIND_MESSAGE, Some(c), frame::encode_timed(&[(msg.clone(), *timing)]))
+            }
+            Event::Unsubscribed => (KIND_UNSUBSCRIBED, None, vec![]),
+        };
+        let record = client.map(|c| c.to_record()).unwrap_or_default();
+        let mut payload = Vec::with_capacity(5 + record.len() + extra.len());
+        payload.push(kind);
+        payload.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&record);
+        payload.extend_from_slice(&extra);
+        frame::encode(&[Message::Bytes(payload)])
+    }
+
+    fn decode_payload(payload: &[u8]) -> Result<Self> {
+        let header = payload.get(..5)
+            .ok_or_else(|| format!("event of {} bytes is too short", payload.len()))?;
+        let kind = header[0];
+        if kind == KIND_UNSUBSCRIBED {
+            return Ok(Event::Unsubscribed);
+        }
+        let record_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let record = payload.get(5..5 + record_len)
+            .ok_or("event record runs past the end of the event")?;
+        let client = ClientHandle::from_record(record)?;
+        let extra = &payload[5 + record_len..];
+        match kind {
+            KIND_JOINED => Ok(Event::Joined(client)),
+            KIND_LEFT => Ok(Event::Left(client)),
+            KIND_RENAMED => Ok(Event::Renamed {
+                client,
+                old_name: String::from_utf8_lossy(extra).into_owned(),
+            }),
+            KIND_SUSPENDED => Ok(Event::Suspended(client)),
+            KIND_RECONNECTED => Ok(Event::Reconnected(client)),
+            KIND_MESSAGE => {
+                let (msg, timing) = frame::decode_timed(extra)?.pop()
+                    .ok_or("message event without a message")?;
+                let timing = timing.unwrap_or_else(|| Timing::at(SystemTime::now()));
+                Ok(Event::Message(client, msg, timing))
+            }
+            _ => Err(format!("unknown event kind {}", kind).into()),
+        }
+    }
+}
+
+/* Split the contents of the events object back into events.
+ */
+pub fn decode(data: &[u8]) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    for msg in frame::decode(data)? {
+        if let Message::Bytes(payload) = msg {
+            events.push(Event::decode_payload(&payload)?);
+        }
+    }
+    Ok(events)
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientState;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn client(name: &str) -> ClientHandle {
+        let joined = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        ClientHandle::new("0a9574c70a06d95f-3", 3, name, joined, ClientState::Connected)
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        let sent = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let timing = Timing { received_at: sent + Duration::from_millis(5), sent_at: sent };
+        let events = vec![
+            Event::Joined(client("Otter")),
+            Event::Renamed { client: client("Ünïcode Otter"), old_name: "Otter".to_string() },
+            Event::Suspended(client("Ünïcode Otter")),
+            Event::Reconnected(client("Ünïcode Otter")),
+            Event::Message(client("Ünïcode Otter"), Message::Bytes(vec![0, 1, 0xff]), timing),
+            Event::Message(client("Ünïcode Otter"), Message::Text("hi".to_string()), timing),
+            Event::Left(client("Ünïcode Otter")),
+            Event::Unsubscribed,
+        ];
+        let data: Vec<u8> = events.iter().flat_map(|e| e.encode()).collect();
+        let decoded = decode(&data).unwrap();
+        assert_eq!(decoded, events);
+        // ClientHandles only compare ids, so check the rest by hand
+        match &decoded[1] {
+            Event::Renamed { client, old_name } => {
+                assert_eq!(client.name(), "Ünïcode Otter");
+                assert_eq!(old_name, "Otter");
+            }
+            other => panic!("expected Renamed, got {:?}", other),
+        }
+        match &decoded[4] {
+            Event::Message(client, _, t) => {
+                assert_eq!(client.name(), "Ünïcode Otter");
+                assert_eq!(*t, timing);
+            }
+            other => panic!("expected Message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_events() {
+        let data = Event::Joined(client("Otter")).encode();
+        frame::tests::assert_rejects_truncations(&data, decode);
+        let payload = match frame::decode(&data).unwrap().pop() {
+            Some(Message::Bytes(payload)) => payload,
+            other => panic!("expected a Bytes frame, got {:?}", other),
+        };
+        // a message event with no message after the record
+        let mut no_message = payload.clone();
+        no_message[0] = KIND_MESSAGE;
+        assert!(decode(&frame::encode(&[Message::Bytes(no_message)])).is_err());
+        // a kind of event this version doesn't know
+        let mut unknown = payload;
+        unknown[0] = 0x42;
+        assert!(decode(&frame::encode(&[Message::Bytes(unknown)])).is_err());
+    }
+}
+
+//==================================<===|===>===================================
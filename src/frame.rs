@@ -0,0 +1,280 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::ipc::Result;
+
+//=================================== Notes ====================================
+/*
+Everything stored in an IPC object is a sequence of frames:
+
+[version: u8][tag: u8][len: u32 LE][payload: len bytes]
+
+The version lets a game and a server built from different releases notice
+that they don't speak the same format instead of misreading each other. The
+tag says what the payload is. Because every frame carries its own length,
+payloads may contain any bytes (including NUL) and a text payload that isn't
+valid utf8 only affects that one message.
+
+Messages from clients may be preceded by a timing frame whose payload is
+
+[received: u64 LE][sent: u64 LE]
+
+in microseconds since the Unix epoch (see Timing). It belongs to the message
+frame that follows it.
+*/
+
+//================================= Constants ==================================
+pub const VERSION: u8 = 1;
+//
+const TAG_TEXT: u8 = 1;
+const TAG_BYTES: u8 = 2;
+const TAG_TIMING: u8 = 3;
+//
+const HEADER_LEN: usize = 6;
+
+//================================== Message ===================================
+/// A single message between the game and a control pad client
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+//=================================== Timing ===================================
+/// When a message from a control pad client got to the server and when the
+/// client (probably) sent it, both by the server's clock (which is also the
+/// game's when they run on the same machine).
+///
+/// sent_at is only as good as what the client tells the server: pages that
+/// take part in clock sync and stamp their messages (see the README) get the
+/// time they sent each message, others get received_at minus half the round
+/// trip time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    pub received_at: SystemTime,
+    pub sent_at: SystemTime,
+}
+
+impl Timing {
+    /// A message that was sent and received at time (e.g. one from the
+    /// server itself)
+    pub fn at(time: SystemTime) -> Self {
+        Timing { received_at: time, sent_at: time }
+    }
+
+    /// How long the message took to get from the client to the server
+    pub fn transit(&self) -> Duration {
+        self.received_at.duration_since(self.sent_at).unwrap_or_default()
+    }
+
+    fn to_payload(self) -> [u8; 16] {
+        let micros = |t: SystemTime| {
+            let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+            u64::try_from(since_epoch.as_micros()).unwrap_or(u64::MAX)
+        };
+        let mut payload = [0u8; 16];
+        payload[..8].copy_from_slice(&micros(self.received_at).to_le_bytes());
+        payload[8..].copy_from_slice(&micros(self.sent_at).to_le_bytes());
+        payload
+    }
+
+    fn from_payload(payload: &[u8]) -> Result<Self> {
+        let bytes: [u8; 16] = payload.try_into()
+            .map_err(|_| format!("timing frame of {} bytes (expected 16)", payload.len()))?;
+        let time = |b: &[u8]| {
+            UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(b.try_into().unwrap()))
+        };
+        Ok(Timing { received_at: time(&bytes[..8]), sent_at: time(&bytes[8..]) })
+    }
+}
+
+//================================== Framing ===================================
+fn push_frame(out: &mut Vec<u8>, tag: u8, payload: &[u8]) {
+    out.push(VERSION);
+    out.push(tag);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+/* Append the frame for *msg* to *out*.
+ */
+pub fn encode_into(out: &mut Vec<u8>, msg: &Message) {
+    match msg {
+        Message::Text(s) => push_frame(out, TAG_TEXT, s.as_bytes()),
+        Message::Bytes(b) => push_frame(out, TAG_BYTES, b),
+    }
+}
+
+/* Append the timing frame for *msg* and then its frame to *out*.
+ */
+pub fn encode_timed_into(out: &mut Vec<u8>, msg: &Message, timing: Timing) {
+    push_frame(out, TAG_TIMING, &timing.to_payload());
+    encode_into(out, msg);
+}
+
+/* The frames for all of *msgs*, ready to be written to an IPC object.
+ */
+pub fn encode(msgs: &[Message]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for msg in msgs {
+        encode_into(&mut out, msg);
+    }
+    out
+}
+
+/* Like encode() but with the timing of each message.
+ */
+pub fn encode_timed(msgs: &[(Message, Timing)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (msg, timing) in msgs {
+        encode_timed_into(&mut out, msg, *timing);
+    }
+    out
+}
+
+/* Split the contents of an IPC object back into messages. Text that isn't
+ * valid utf8 is repaired (with U+FFFD) rather than failing the batch, but a
+ * frame from another version or one that runs past the end of *data* means
+ * the rest can't be trusted, so that's an error.
+ */
+pub fn decode(data: &[u8]) -> Result<Vec<Message>> {
+    Ok(decode_timed(data)?.into_iter().map(|(msg, _)| msg).collect())
+}
+
+/* Like decode() but keeping the timing of the messages that have one.
+ */
+pub fn decode_timed(data: &[u8]) -> Result<Vec<(Message, Option<Timing>)>> {
+    let mut msgs = Vec::new();
+    let mut timing = None;
+    let mut rest = data;
+    while !rest.is_empty() {
+        if rest.len() < HEADER_LEN {
+            return Err(format!("truncated frame header ({} bytes)", rest.len()).into());
+        }
+        if rest[0] != VERSION {
+            return Err(format!("unsupported frame version {} (expected {})",
+                               rest[0], VERSION).into());
+        }
+        let tag = rest[1];
+        let len = u32::from_le_bytes([rest[2], rest[3], rest[4], rest[5]]) as usize;
+        let payload = rest.get(HEADER_LEN..HEADER_LEN + len).ok_or_else(|| {
+            format!("frame of {} bytes runs past the end of the data", len)
+        })?;
+        match tag {
+            TAG_TEXT => {
+                let text = String::from_utf8_lossy(payload).into_owned();
+                msgs.push((Message::Text(text), timing.take()));
+            }
+            TAG_BYTES => msgs.push((Message::Bytes(payload.to_vec()), timing.take())),
+            TAG_TIMING => timing = Some(Timing::from_payload(payload)?),
+            // a newer writer may add kinds of messages we don't know about
+            // but the length still lets us skip them
+            _ => println!("Warning: skipping frame with unknown tag {}", tag),
+        }
+        rest = &rest[HEADER_LEN + len..];
+    }
+    Ok(msgs)
+}
+
+/* Like decode() but for objects that only ever hold text (e.g. rpc_out).
+ */
+pub fn decode_text(data: &[u8]) -> Result<Vec<String>> {
+    Ok(decode(data)?.into_iter().filter_map(|msg| match msg {
+        Message::Text(s) => Some(s),
+        Message::Bytes(_) => None,
+    }).collect())
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /* For the formats built on frames: checks that *decode* rejects
+     * *encoded*, a single frame holding a Bytes payload, cut short anywhere,
+     * whether the frame itself is cut off or it's whole but holds a cut off
+     * payload.
+     */
+    pub(crate) fn assert_rejects_truncations<T: std::fmt::Debug>(
+        encoded: &[u8], decode: impl Fn(&[u8]) -> Result<T>) {
+        for len in 1..encoded.len() {
+            if let Ok(decoded) = decode(&encoded[..len]) {
+                panic!("frame cut to {} of {} bytes decoded as {:?}",
+                       len, encoded.len(), decoded);
+            }
+        }
+        let payload = match super::decode(encoded).unwrap().pop() {
+            Some(Message::Bytes(payload)) => payload,
+            other => panic!("expected a Bytes frame, got {:?}", other),
+        };
+        for len in 0..payload.len() {
+            let cut = encode(&[Message::Bytes(payload[..len].to_vec())]);
+            if let Ok(decoded) = decode(&cut) {
+                panic!("payload cut to {} of {} bytes decoded as {:?}",
+                       len, payload.len(), decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_text_and_bytes() {
+        let msgs = vec![
+            Message::Text("with\0nul".to_string()),
+            Message::Bytes(vec![0, 0xff, 0, 7]),
+            Message::Text(String::new()),
+        ];
+        assert_eq!(decode(&encode(&msgs)).unwrap(), msgs);
+    }
+
+    #[test]
+    fn bad_utf8_only_affects_its_own_message() {
+        let mut data = encode(&[Message::Text("ok".to_string())]);
+        data.extend_from_slice(&[VERSION, TAG_TEXT, 2, 0, 0, 0, 0xc3, 0x28]);
+        encode_into(&mut data, &Message::Text("still ok".to_string()));
+        let msgs = decode_text(&data).unwrap();
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[0], "ok");
+        assert_eq!(msgs[2], "still ok");
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_frames() {
+        let data = encode(&[Message::Bytes(vec![1, 2, 3])]);
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(b"hello\0").is_err());
+    }
+
+    #[test]
+    fn timing_belongs_to_the_next_message() {
+        let sent = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let timing = Timing { received_at: sent + Duration::from_millis(12), sent_at: sent };
+        let mut data = encode_timed(&[(Message::Text("timed".to_string()), timing)]);
+        encode_into(&mut data, &Message::Bytes(vec![1]));
+        let msgs = decode_timed(&data).unwrap();
+        assert_eq!(msgs, vec![(Message::Text("timed".to_string()), Some(timing)),
+                              (Message::Bytes(vec![1]), None)]);
+        assert_eq!(timing.transit(), Duration::from_millis(12));
+        // readers that don't care about timing don't see it
+        assert_eq!(decode(&data).unwrap().len(), 2);
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,372 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>=================================//
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+//=================================== Notes ==================================//
+/*
+Phones load the controller page from the same port they open their websocket
+on. saws::Server reads each new connection's request; websocket upgrades go on
+to tungstenite and everything else is answered here from the directories of
+static files mounted on the server (--www at / and the game's controller, see
+controlpads::controller).
+
+Only GET and HEAD are supported and every response closes the connection.
+Files are revalidated with their ETag on every load (so a game's new page shows
+up right away but unchanged files aren't sent again) and text is gzipped for
+browsers that accept it. Responses are made on the server's event loop, so
+each file is only compressed once: the gzipped body is kept with the ETag it
+was made for and redone when the file's ETag changes.
+*/
+
+//================================= Constants ================================//
+// requests (just the head, bodies are ignored) larger than this are refused
+pub const MAX_REQUEST_BYTES: usize = 16 * 1024;
+// files smaller than this aren't worth compressing
+const MIN_GZIP_BYTES: u64 = 1024;
+
+//================================== Request =================================//
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    // Out: the request in buf, or None if it isn't all there yet (or isn't
+    //      HTTP at all, which the caller finds out when it stops growing)
+    pub fn parse(buf: &[u8]) -> Option<Request> {
+        let end = buf.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let head = String::from_utf8_lossy(&buf[..end]);
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?.to_string();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Request { method, target, headers })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("websocket"))
+    }
+
+    fn accepts_gzip(&self) -> bool {
+        self.header("accept-encoding").is_some_and(|v| {
+            v.split(',').any(|coding| {
+                let mut parts = coding.split(';');
+                let name = parts.next().unwrap_or("").trim();
+                // "gzip;q=0" means anything but gzip
+                let refused = parts.any(|p| p.trim().replace(' ', "") == "q=0");
+                name.eq_ignore_ascii_case("gzip") && !refused
+            })
+        })
+    }
+}
+
+//================================== Helpers =================================//
+fn mime_type(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("")
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "json" | "map" => "application/json",
+        "wasm" => "application/wasm",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn compressible(mime: &str) -> bool {
+    mime.starts_with("text/") || mime.starts_with("application/json") ||
+        mime == "application/wasm" || mime == "image/svg+xml"
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn response(status: &str, headers: &[(&str, String)], body: &[u8],
+            with_body: bool) -> Vec<u8> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                           status, body.len());
+    for (name, value) in headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+    let mut ret = head.into_bytes();
+    if with_body {
+        ret.extend_from_slice(body);
+    }
+    ret
+}
+
+fn error_response(status: &str, with_body: bool) -> Vec<u8> {
+    let headers = [("Content-Type", "text/plain; charset=utf-8".to_string())];
+    response(status, &headers, status.as_bytes(), with_body)
+}
+
+//================================ StaticFiles ===============================//
+// the files in root, served under prefix (which starts and ends with '/')
+pub struct StaticFiles {
+    prefix: String,
+    root: PathBuf,
+    // compressed bodies by file, with the ETag each one was made for
+    gzipped: HashMap<PathBuf, (String, Vec<u8>)>,
+}
+
+impl StaticFiles {
+    pub fn new(prefix: &str, root: PathBuf) -> Self {
+        StaticFiles { prefix: prefix.to_string(), root, gzipped: HashMap::new() }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn serves(&self, target: &str) -> bool {
+        target.starts_with(&self.prefix)
+    }
+
+    // the file target asks for, if it's inside our directory
+    fn resolve(&self, target: &str) -> Option<PathBuf> {
+        let path = target.strip_prefix(&self.prefix)?;
+        let path = path.split(['?', '#']).next().unwrap_or("");
+        let path = percent_decode(path)?;
+        let mut file = self.root.clone();
+        for component in path.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => return None,
+                c if c.contains('\\') || c.contains('\0') => return None,
+                c => file.push(c),
+            }
+        }
+        if file.is_dir() {
+            file.push("index.html");
+        }
+        // don't follow links out of the directory
+        let file = file.canonicalize().ok()?;
+        if !file.starts_with(self.root.canonicalize().ok()?) || !file.is_file() {
+            return None;
+        }
+        Some(file)
+    }
+
+    // file gzipped, from the cache unless its ETag has changed since
+    fn gzipped(&mut self, file: &Path, etag: &str) -> std::io::Result<Vec<u8>> {
+        if let Some((cached_etag, body)) = self.gzipped.get(file) {
+            if cached_etag == etag {
+                return Ok(body.clone());
+            }
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&std::fs::read(file)?)?;
+        let body = encoder.finish()?;
+        self.gzipped.insert(file.to_path_buf(), (etag.to_string(), body.clone()));
+        Ok(body)
+    }
+
+    // the complete response to request
+    pub fn respond(&mut self, request: &Request) -> Vec<u8> {
+        let with_body = request.method != "HEAD";
+        if request.method != "GET" && request.method != "HEAD" {
+            return error_response("405 Method Not Allowed", with_body);
+        }
+        let file = match self.resolve(&request.target) {
+            Some(file) => file,
+            None => return error_response("404 Not Found", with_body),
+        };
+        let metadata = match std::fs::metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(_) => return error_response("404 Not Found", with_body),
+        };
+        let mime = mime_type(&file);
+        let gzip = request.accepts_gzip() && compressible(mime) &&
+            metadata.len() >= MIN_GZIP_BYTES;
+        let modified = metadata.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}{}\"", metadata.len(), modified,
+                           if gzip { "-gz" } else { "" });
+        let mut headers = vec![
+            ("ETag", etag.clone()),
+            ("Cache-Control", "no-cache".to_string()),
+            ("Vary", "Accept-Encoding".to_string()),
+        ];
+        let not_modified = request.header("if-none-match")
+            .is_some_and(|tags| tags.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+        if not_modified {
+            return response("304 Not Modified", &headers, &[], false);
+        }
+        headers.push(("Content-Type", mime.to_string()));
+        if gzip {
+            match self.gzipped(&file, &etag) {
+                Ok(body) => {
+                    headers.push(("Content-Encoding", "gzip".to_string()));
+                    return response("200 OK", &headers, &body, with_body);
+                }
+                Err(e) => println!("Warning: failed to compress {}: {}", file.display(), e),
+            }
+        }
+        let body = match std::fs::read(&file) {
+            Ok(body) => body,
+            Err(e) => {
+                println!("Warning: failed to read {}: {}", file.display(), e);
+                return error_response("500 Internal Server Error", with_body);
+            }
+        };
+        response("200 OK", &headers, &body, with_body)
+    }
+}
+
+// the response to requests when there are no files to serve
+pub fn not_found(request: &Request) -> Vec<u8> {
+    error_response("404 Not Found", request.method != "HEAD")
+}
+
+//=================================== Tests ==================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+
+    fn get(files: &mut StaticFiles, target: &str, headers: &str) -> (String, Vec<u8>) {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: x\r\n{}\r\n", target, headers);
+        let response = files.respond(&Request::parse(raw.as_bytes()).unwrap());
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        (String::from_utf8(response[..end].to_vec()).unwrap(), response[end + 4..].to_vec())
+    }
+
+    #[test]
+    fn serves_files_with_types_etags_and_gzip() {
+        let dir = tempfile::tempdir().unwrap();
+        let page = "<canvas></canvas>".repeat(100);
+        std::fs::write(dir.path().join("index.html"), &page).unwrap();
+        std::fs::write(dir.path().join("pad.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        let mut files = StaticFiles::new("/", dir.path().to_path_buf());
+
+        let (head, body) = get(&mut files, "/", "");
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(head.contains("Content-Type: text/html"), "{}", head);
+        assert_eq!(body, page.as_bytes());
+        let etag = head.lines().find_map(|l| l.strip_prefix("ETag: ")).unwrap();
+        let (head, body) = get(&mut files, "/index.html", &format!("If-None-Match: {}\r\n", etag));
+        assert!(head.starts_with("HTTP/1.1 304"), "{}", head);
+        assert!(body.is_empty());
+
+        let (head, body) = get(&mut files, "/index.html?v=2", "Accept-Encoding: gzip, br\r\n");
+        assert!(head.contains("Content-Encoding: gzip"), "{}", head);
+        let mut unzipped = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut unzipped).unwrap();
+        assert_eq!(unzipped, page);
+
+        let (head, _) = get(&mut files, "/pad.png", "Accept-Encoding: gzip\r\n");
+        assert!(head.contains("Content-Type: image/png"), "{}", head);
+        assert!(!head.contains("Content-Encoding"), "{}", head);
+    }
+
+    #[test]
+    fn stays_inside_its_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let www = dir.path().join("www");
+        std::fs::create_dir(&www).unwrap();
+        std::fs::write(dir.path().join("secret"), "shh").unwrap();
+        let mut files = StaticFiles::new("/pad/", www);
+        for target in ["/pad/../secret", "/pad/%2e%2e/secret", "/pad/missing.js", "/secret"] {
+            let (head, _) = get(&mut files, target, "");
+            assert!(head.starts_with("HTTP/1.1 404"), "{}: {}", target, head);
+        }
+    }
+
+    #[test]
+    fn compresses_again_only_when_the_file_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pad.js");
+        let unzip = |body: Vec<u8>| {
+            let mut unzipped = String::new();
+            GzDecoder::new(&body[..]).read_to_string(&mut unzipped).unwrap();
+            unzipped
+        };
+        let old = "let pad = 1;\n".repeat(100);
+        std::fs::write(&path, &old).unwrap();
+        let mut files = StaticFiles::new("/", dir.path().to_path_buf());
+        let (_, body) = get(&mut files, "/pad.js", "Accept-Encoding: gzip\r\n");
+        assert_eq!(unzip(body), old);
+        // an unchanged file is served from the cache
+        let cached = files.gzipped.values_mut().next().unwrap();
+        cached.1 = b"cached".to_vec();
+        let (_, body) = get(&mut files, "/pad.js", "Accept-Encoding: gzip\r\n");
+        assert_eq!(body, b"cached");
+
+        let new = "let pad = 22;\n".repeat(100);
+        std::fs::write(&path, &new).unwrap();
+        let (_, body) = get(&mut files, "/pad.js", "Accept-Encoding: gzip\r\n");
+        assert_eq!(unzip(body), new);
+        assert_eq!(files.gzipped.len(), 1);
+    }
+}
+//==================================<===|===>=================================//
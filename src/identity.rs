@@ -0,0 +1,143 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>=================================//
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rand::RngCore;
+
+//=================================== Notes ==================================//
+/*
+A session token looks like "<identity>.<signature>" where identity is 16 hex
+characters of randomness chosen by the server and signature is the first 16
+bytes (hex encoded) of HMAC-SHA256(key, identity). The key is generated when
+the server starts so tokens stay valid for the lifetime of the server process.
+Browsers hold on to the token and present it whenever they reconnect, which
+lets a phone keep its identity when its IP address changes or when several
+phones sit behind the same NAT.
+*/
+
+//================================= Constants ================================//
+const IDENTITY_BYTES: usize = 8;
+const SIGNATURE_BYTES: usize = 16;
+
+//================================== Helpers =================================//
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i+2], 16).ok())
+        .collect()
+}
+
+//================================ TokenIssuer ===============================//
+pub struct TokenIssuer {
+    key: [u8; 32],
+}
+
+impl TokenIssuer {
+    pub fn new() -> Self {
+        let mut key = [0_u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        TokenIssuer { key }
+    }
+
+    fn mac(&self, identity: &str) -> Hmac<Sha256> {
+        // unwrap because HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(identity.as_bytes());
+        mac
+    }
+
+    // Out: (the token to hand to the browser, the identity it encodes)
+    pub fn issue(&self) -> (String, String) {
+        let mut identity_bytes = [0_u8; IDENTITY_BYTES];
+        rand::thread_rng().fill_bytes(&mut identity_bytes);
+        let identity = to_hex(&identity_bytes);
+        let signature = self.mac(&identity).finalize().into_bytes();
+        let token = format!("{}.{}", identity, to_hex(&signature[..SIGNATURE_BYTES]));
+        (token, identity)
+    }
+
+    // Out: the identity encoded in token if the token was issued by us
+    pub fn verify(&self, token: &str) -> Option<String> {
+        let (identity, signature_hex) = token.split_once('.')?;
+        if identity.len() != IDENTITY_BYTES*2 || from_hex(identity).is_none() {
+            return None;
+        }
+        let signature = from_hex(signature_hex)?;
+        if signature.len() != SIGNATURE_BYTES {
+            return None;
+        }
+        self.mac(identity).verify_truncated_left(&signature).ok()?;
+        Some(identity.to_string())
+    }
+}
+
+//=================================== Tests ==================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let issuer = TokenIssuer::new();
+        let (token, identity) = issuer.issue();
+        assert_eq!(issuer.verify(&token), Some(identity.clone()));
+        let (other_token, other_identity) = issuer.issue();
+        assert_ne!(other_identity, identity);
+        assert_eq!(issuer.verify(&other_token), Some(other_identity));
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let issuer = TokenIssuer::new();
+        let (token, _) = issuer.issue();
+        let (identity, signature) = token.split_once('.').unwrap();
+        // someone else's identity with our signature
+        let (_, stranger) = issuer.issue();
+        assert_eq!(issuer.verify(&format!("{}.{}", stranger, signature)), None);
+        // a flipped bit in the signature
+        let last = signature.chars().last().unwrap();
+        let flipped = if last == '0' { '1' } else { '0' };
+        let forged = format!("{}.{}{}", identity, &signature[..signature.len() - 1], flipped);
+        assert_eq!(issuer.verify(&forged), None);
+        // cut short, padded, mangled
+        assert_eq!(issuer.verify(&token[..token.len() - 2]), None);
+        assert_eq!(issuer.verify(&format!("{}00", token)), None);
+        assert_eq!(issuer.verify(identity), None);
+        assert_eq!(issuer.verify(""), None);
+        assert_eq!(issuer.verify(&token.replace('.', ":")), None);
+        assert_eq!(issuer.verify(&format!("{}.{}", "zz".repeat(IDENTITY_BYTES), signature)), None);
+    }
+
+    // a token from another server (e.g. before a restart) isn't ours
+    #[test]
+    fn rejects_foreign_tokens() {
+        let (token, _) = TokenIssuer::new().issue();
+        assert_eq!(TokenIssuer::new().verify(&token), None);
+    }
+}
+
+//==================================<===|===>=================================//
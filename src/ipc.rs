@@ -19,124 +19,703 @@
 
 #![allow(dead_code)]
 
+use std::collections::HashMap;
 use std::io::{Read, Write, Seek, SeekFrom};
 use std::path::Path;
 use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
+use crate::{config, event, multicast, stats};
 use crate::systemlock::Locked;
+use crate::ring::Ring;
+#[cfg(unix)]
+use crate::ipcsock;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T> = std::result::Result<T, Error>;
 
-#[cfg(target_os = "macos")]
-const IPC_PATH: &str = "/var/tmp/";
-#[cfg(target_os = "linux")]
-//const IPC_PATH: &str = "/home/requin/ipc/";
-const IPC_PATH: &str = "/dev/shm/rqnio/";
-#[cfg(target_os = "windows")]
-const IPC_PATH: &str = "C:\\Users\\gamenite\\";
+// name of the datagram socket the server listens on so that games can wake it
+// up after writing to an IPC object
+const WAKE_NAME: &str = "wake";
+// and the one a game listens on while it waits for the server
+const GAME_WAKE_NAME: &str = "game_wake";
+// how often a game that can't listen on GAME_WAKE_NAME checks for itself
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// name of the stream socket games connect to when using Transport::Socket
+const SOCKET_NAME: &str = "ipc.sock";
+// environment variable selecting the transport: "files" (default), "socket"
+// or "ring"
+const TRANSPORT_VAR: &str = "CONTROLPAD_IPC";
+// suffix of the files backing ring buffers
+const RING_SUFFIX: &str = ".ring";
+
+
+// the directory all IPC objects live in (see config::Dirs)
+fn ipc_path() -> Result<String> {
+    Ok(config::dirs()?.ipc_path())
+}
 
 
 pub fn initialize() {
     //#[cfg(debug_assertions)] println!("ipc initialize");
-    if !std::path::Path::new(IPC_PATH).exists() {
-	std::fs::create_dir(IPC_PATH)
+    let ipc_path = ipc_path().unwrap_or_else(|e| panic!("Fatal Error: {}", e));
+    if !std::path::Path::new(&ipc_path).exists() {
+	std::fs::create_dir_all(&ipc_path)
             .unwrap_or_else(|e| {
                 let help_msg = format!(
                     "Try creating {} yourself and giving yourself permission to \
                      make files within that directory",
-                    IPC_PATH
+                    ipc_path
                 );
                 panic!("Fatal Error: Could not create {}: {}\n{}",
-                       IPC_PATH, e, help_msg);
+                       ipc_path, e, help_msg);
             });
     }
-    // TODO: delete previous IPC data in the dir
+    // data left behind by a previous server is deleted by purge()
+}
+
+//================================ IpcBackend ==================================
+/* An IPC object is a named, append-only mailbox of bytes with a "dirty" flag.
+ * The server and the game each hold a backend and must agree on which kind.
+ * What goes in the bytes is up to the caller (see frame.rs).
+ */
+pub trait IpcBackend: Send + Sync {
+    /* Atomically append to the IPC object with *name*.
+     */
+    fn write(&self, name: &str, data: &[u8]) -> Result<()>;
+
+    /* Atomically read the contents of the IPC object with *name*.
+     */
+    fn read(&self, name: &str) -> Result<Vec<u8>>;
+
+    /* Atomically read and erase the contents of the ipc object with *name*.
+     * Counts as a read.
+     */
+    fn consume(&self, name: &str) -> Result<Vec<u8>>;
+
+    /* Return true if the ipc object with *name* has been written to since the
+     * last read (or consume).
+     */
+    fn has_new(&self, name: &str) -> Result<bool>;
+
+    /* Let the server know that an IPC object it reads has been written to.
+     * Backends where the server can't miss a write don't need to do anything.
+     */
+    fn notify_server(&self) {
+    }
+
+    /* Let the game know that an IPC object it reads has been written to.
+     */
+    fn notify_game(&self) {
+        send_game_wake();
+    }
+
+    /* Game side: sleep until the server calls notify_game() or *timeout*
+     * passes. A notification that came since the last wait counts, so
+     * nothing is missed between checking for data and waiting, but callers
+     * should still expect to wake up with nothing new.
+     */
+    fn wait_for_server(&self, timeout: Duration) {
+        wait_for_game_wake(timeout);
+    }
+
+    /* Whether a server is running to read and write the other end of our
+     * IPC objects.
+     */
+    fn server_available(&self) -> bool {
+        crate::systemlock::server_running()
+    }
+}
+
+//================================= Transport ==================================
+/* Which backend to use, chosen at runtime with CONTROLPAD_IPC. The server and
+ * games must be started with the same one.
+ *   Files:  each object is a file in the IPC directory (config::Dirs)
+ *           guarded by a systemlock
+ *   Socket: the server holds the objects in memory and games talk to it over
+ *           a unix domain socket in the IPC directory
+ *   Ring:   the <id>_in and <id>_out objects are memory-mapped ring buffers
+ *           in the IPC directory (everything else is still Files)
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    Files,
+    Socket,
+    Ring,
+}
+
+pub fn transport() -> Transport {
+    match std::env::var(TRANSPORT_VAR).as_deref() {
+        Ok("socket") if cfg!(unix) => Transport::Socket,
+        Ok("socket") => {
+            println!("Warning: socket IPC is not supported on this \
+                      platform, using files");
+            Transport::Files
+        }
+        Ok("ring") => Transport::Ring,
+        Ok("files") | Err(_) => Transport::Files,
+        Ok(other) => {
+            println!("Warning: unknown {} '{}', using files",
+                     TRANSPORT_VAR, other);
+            Transport::Files
+        }
+    }
+}
+
+fn socket_path() -> Result<String> {
+    Ok(format!("{}{}", ipc_path()?, SOCKET_NAME))
+}
+
+/* The backend a game should use for the configured transport.
+ */
+pub fn game_backend() -> Arc<dyn IpcBackend> {
+    match transport() {
+        Transport::Files => Arc::new(FileBackend),
+        Transport::Ring => Arc::new(RingBackend::new()),
+        #[cfg(unix)]
+        Transport::Socket => match socket_path() {
+            Ok(path) => Arc::new(ipcsock::SocketBackend::new(&path)),
+            // with no directory to find the socket in, a FileBackend at
+            // least says why every operation fails
+            Err(_) => Arc::new(FileBackend),
+        },
+        #[cfg(not(unix))]
+        Transport::Socket => Arc::new(FileBackend),
+    }
+}
+
+/* The backend the server should use for the configured transport. Called by
+ * the server after initialize(); with Transport::Socket this also starts
+ * accepting games.
+ */
+pub fn server_backend() -> Result<Arc<dyn IpcBackend>> {
+    match transport() {
+        #[cfg(unix)]
+        Transport::Socket => {
+            let memory: Arc<dyn IpcBackend> = Arc::new(MemoryBackend::hosting());
+            ipcsock::host(&socket_path()?, memory.clone())?;
+            Ok(memory)
+        }
+        _ => Ok(game_backend()),
+    }
+}
+
+//=================================== Files ====================================
+// Every object is a file in the IPC directory. The first byte of the file is
+// the dirty flag and the rest is the data.
+pub struct FileBackend;
+
+impl IpcBackend for FileBackend {
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        //#[cfg(debug_assertions)] println!("ipc write: name: {}, data: {}", name, data);
+        let lock = Locked::new(name)?;
+        let path = format!("{}{}", ipc_path()?, name);
+        if Path::new(&path).exists() {
+            //#[cfg(debug_assertions)] println!("existing file");
+    	let mut f = File::options().write(true).open(&path)?;
+    	f.seek(SeekFrom::Start(0))?;
+    	f.write_all(&[1_u8])?;
+    	f.seek(SeekFrom::End(0))?;
+    	f.write_all(data)?;
+        } else {
+            //#[cfg(debug_assertions)] println!("new file");
+    	let mut f = File::options().create(true).truncate(false).write(true).open(&path)?;
+    	f.write_all(&[1_u8])?;
+    	f.write_all(data)?;
+        }
+        lock.unlock()?;
+        Ok(())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        //#[cfg(debug_assertions)] println!("ipc read: name: {}", name);
+        let lock = Locked::new(name)?;
+        let path = format!("{}{}", ipc_path()?, name);
+        if ! Path::new(&path).exists() {
+            //#[cfg(debug_assertions)] println!("no file to read");
+    	return Ok(Vec::new());
+        }
+        let mut f = File::options().read(true).write(true).open(&path)?;    
+        let mut s = Vec::new();
+        f.write_all(&[0_u8])?;
+        f.read_to_end(&mut s)?;
+        lock.unlock()?;
+        Ok(s)
+    }
+
+    fn consume(&self, name: &str) -> Result<Vec<u8>> {
+        //#[cfg(debug_assertions)] println!("ipc consume: name: {}", name);
+        let lock = Locked::new(name)?;
+        let path = format!("{}{}", ipc_path()?, name);
+        if ! Path::new(&path).exists() {
+            return Ok(Vec::new());
+        }
+        let mut f = File::options().read(true).write(true).open(&path)?;    
+        let mut s = Vec::new();
+        f.seek(SeekFrom::Start(1))?;
+        f.read_to_end(&mut s)?;
+        std::fs::remove_file(&path)?;
+        let mut f_new = File::options().create(true).truncate(false).write(true).open(&path)?;
+        f_new.write_all(&[1_u8])?;
+        lock.unlock()?;
+        Ok(s)
+    }
+
+    fn has_new(&self, name: &str) -> Result<bool> {
+        //#[cfg(debug_assertions)] println!("has_new: {}", name);
+        let lock = Locked::new(name)?;
+        let path = format!("{}{}", ipc_path()?, name);
+        if ! Path::new(&path).exists() {
+            //#[cfg(debug_assertions)] println!("no file to check");
+    	return Ok(false);
+        }
+        let mut f = File::options().read(true).write(false).create(false).open(&path)?;    
+        if f.metadata()?.len() == 0 {
+    	return Ok(false);
+        }
+        let mut buf = [0_u8];
+        f.read_exact(&mut buf)?;
+        lock.unlock()?;
+        Ok(buf[0] != 0)
+    }
+
+    fn notify_server(&self) {
+        send_wake();
+    }
+}
+
+//=================================== Memory ===================================
+// Objects live in this process. The server uses one of these for
+// Transport::Socket, and tests can share one between a server and a game.
+struct Object {
+    dirty: bool,
+    data: Vec<u8>,
+}
+
+// notify_game() bumps notified and wait_for_server() waits for it to pass seen
+#[derive(Default)]
+struct Notifications {
+    notified: u64,
+    seen: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    objects: Arc<Mutex<HashMap<String, Object>>>,
+    notifications: Arc<(Mutex<Notifications>, Condvar)>,
+    // whether games in other processes (Transport::Socket) need waking too
+    wake_games: bool,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        MemoryBackend::default()
+    }
+
+    // the server's backend for Transport::Socket
+    #[cfg(unix)]
+    fn hosting() -> Self {
+        MemoryBackend { wake_games: true, ..MemoryBackend::default() }
+    }
+
+    fn with_objects<T>(&self, f: impl FnOnce(&mut HashMap<String, Object>) -> T) -> T {
+        let mut guard = self.objects.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut guard)
+    }
+}
+
+impl IpcBackend for MemoryBackend {
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.with_objects(|objects| {
+            let obj = objects.entry(name.to_string())
+                .or_insert(Object { dirty: true, data: Vec::new() });
+            obj.dirty = true;
+            obj.data.extend_from_slice(data);
+        });
+        Ok(())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(self.with_objects(|objects| match objects.get_mut(name) {
+            Some(obj) => {
+                obj.dirty = false;
+                obj.data.clone()
+            }
+            None => Vec::new(),
+        }))
+    }
+
+    fn consume(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(self.with_objects(|objects| match objects.get_mut(name) {
+            Some(obj) => {
+                // consuming leaves the object marked dirty just like the file
+                // version does when it recreates the file
+                obj.dirty = true;
+                std::mem::take(&mut obj.data)
+            }
+            None => Vec::new(),
+        }))
+    }
+
+    fn has_new(&self, name: &str) -> Result<bool> {
+        Ok(self.with_objects(|objects| {
+            objects.get(name).map(|obj| obj.dirty).unwrap_or(false)
+        }))
+    }
+
+    // whoever shares this backend with us is the server
+    fn server_available(&self) -> bool {
+        true
+    }
+
+    // the game may be in this process or (with Transport::Socket) another one
+    fn notify_game(&self) {
+        let (lock, condvar) = &*self.notifications;
+        lock.lock().unwrap_or_else(|e| e.into_inner()).notified += 1;
+        condvar.notify_all();
+        if self.wake_games {
+            send_game_wake();
+        }
+    }
+
+    fn wait_for_server(&self, timeout: Duration) {
+        let (lock, condvar) = &*self.notifications;
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (mut guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |n| n.notified == n.seen)
+            .unwrap_or_else(|e| e.into_inner());
+        guard.seen = guard.notified;
+    }
+}
+
+//==================================== Ring ====================================
+// The <id>_in/<id>_out objects each have exactly one writer and one reader so
+// they can be lock-free ring buffers. Everything else goes to files (rpc_out
+// in particular has more than one writer).
+pub struct RingBackend {
+    files: FileBackend,
+    // rings this process has mapped, by object name
+    rings: Mutex<HashMap<String, Arc<Ring>>>,
+}
+
+impl RingBackend {
+    pub fn new() -> Self {
+        RingBackend {
+            files: FileBackend,
+            rings: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_ring(name: &str) -> bool {
+        !name.starts_with("rpc_") && (name.ends_with("_in") || name.ends_with("_out"))
+    }
+
+    // the ring for the object *name*, mapped on first use
+    fn ring(&self, name: &str) -> Result<Arc<Ring>> {
+        let path = format!("{}{}{}", ipc_path()?, name, RING_SUFFIX);
+        self.ring_at(name, &path)
+    }
+
+    // the ring for *name* at *path*, mapped again if the file there isn't
+    // the one we mapped (a server starting up deletes every ring, and the
+    // new file is the one it reads)
+    fn ring_at(&self, name: &str, path: &str) -> Result<Arc<Ring>> {
+        let mut rings = self.rings.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(ring) = rings.get(name) {
+            if ring.is_at(path) {
+                return Ok(ring.clone());
+            }
+        }
+        let ring = Arc::new(Ring::open(path, name, crate::ring::DEFAULT_CAPACITY)?);
+        rings.insert(name.to_string(), ring.clone());
+        Ok(ring)
+    }
+}
+
+impl Default for RingBackend {
+    fn default() -> Self {
+        RingBackend::new()
+    }
+}
+
+impl IpcBackend for RingBackend {
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        if !Self::is_ring(name) {
+            return self.files.write(name, data);
+        }
+        self.ring(name)?.write(data)
+    }
+
+    // rings don't keep anything around after a consume so a read is just a
+    // look at what hasn't been consumed yet
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        if !Self::is_ring(name) {
+            return self.files.read(name);
+        }
+        self.ring(name)?.peek()
+    }
+
+    fn consume(&self, name: &str) -> Result<Vec<u8>> {
+        if !Self::is_ring(name) {
+            return self.files.consume(name);
+        }
+        self.ring(name)?.consume()
+    }
+
+    fn has_new(&self, name: &str) -> Result<bool> {
+        if !Self::is_ring(name) {
+            return self.files.has_new(name);
+        }
+        Ok(self.ring(name)?.has_new())
+    }
+
+    fn notify_server(&self) {
+        send_wake();
+    }
 }
 
-/* Atomically append to the IPC object with *name*.
+//================================== Helpers ===================================
+/* Delete the file behind the IPC object *name* (Files transport).
  */
-pub fn write(name: &str, data: &str) -> Result<()> {
-    //#[cfg(debug_assertions)] println!("ipc write: name: {}, data: {}", name, data);
+pub fn remove_file(name: &str) -> Result<()> {
     let lock = Locked::new(name)?;
-    let path = format!("{}{}", IPC_PATH, name);
+    let path = format!("{}{}", ipc_path()?, name);
     if Path::new(&path).exists() {
-        //#[cfg(debug_assertions)] println!("existing file");
-	let mut f = File::options().write(true).open(&path)?;
-	f.seek(SeekFrom::Start(0))?;
-	f.write_all(&[1 as u8])?;
-	f.seek(SeekFrom::End(0))?;
-	f.write_all(data.as_bytes())?;
-    } else {
-        //#[cfg(debug_assertions)] println!("new file");
-	let mut f = File::options().create(true).write(true).open(&path)?;
-	f.write_all(&[1 as u8])?;
-	f.write_all(data.as_bytes())?;
+        std::fs::remove_file(&path)?;
     }
     lock.unlock()?;
     Ok(())
 }
 
 
-/* Atomically read the contents of the IPC object with *name*.
+/* Whether *file_name* in the IPC directory is one the server or a game
+ * creates: a fixed object, a client's <id>_in/<id>_out (the ids the server
+ * hands out are <16 hex digits>-<number>), a ring behind either, or one of
+ * the sockets. Anything else there belongs to somebody else.
  */
-pub fn read(name: &str) -> Result<String> {
-    //#[cfg(debug_assertions)] println!("ipc read: name: {}", name);
-    let lock = Locked::new(name)?;
-    let path = format!("{}{}",IPC_PATH, name);
-    if ! Path::new(&path).exists() {
-        //#[cfg(debug_assertions)] println!("no file to read");
-	return Ok(String::new());
-    }
-    let mut f = File::options().read(true).write(true).open(&path)?;    
-    let mut s = String::new();
-    f.write_all(&[0 as u8])?;
-    f.read_to_string(&mut s)?;
-    lock.unlock()?;
-    Ok(s)
+fn is_ipc_file(file_name: &str) -> bool {
+    const OBJECTS: &[&str] = &["cp_clients", "rpc_in", "rpc_out", event::EVENTS_OBJECT,
+                               multicast::MULTICAST_OBJECT, stats::STATS_OBJECT];
+    const SOCKETS: &[&str] = &[WAKE_NAME, GAME_WAKE_NAME, SOCKET_NAME];
+    if SOCKETS.contains(&file_name) {
+        return true;
+    }
+    let name = file_name.strip_suffix(RING_SUFFIX).unwrap_or(file_name);
+    if OBJECTS.contains(&name) {
+        return true;
+    }
+    let Some(id) = name.strip_suffix("_in").or_else(|| name.strip_suffix("_out")) else {
+        return false;
+    };
+    match id.split_once('-') {
+        Some((identity, subid)) => {
+            identity.len() == 16 && identity.chars().all(|c| c.is_ascii_hexdigit()) &&
+                !subid.is_empty() && subid.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+/* Delete every IPC object in our namespace along with its lock file. The
+ * server does this when it starts, so that games don't see clients and
+ * messages from a previous run, and again when it exits. Only files named
+ * like IPC objects are touched, so other files (and subdirectories, i.e.
+ * other namespaces) in a shared directory are left alone.
+ */
+pub fn purge() -> Result<()> {
+    purge_in(config::dirs()?)
+}
+
+fn purge_in(dirs: &config::Dirs) -> Result<()> {
+    let entries = match std::fs::read_dir(dirs.ipc_path()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() || !is_ipc_file(&file_name) {
+            continue;
+        }
+        std::fs::remove_file(entry.path())?;
+        let name = file_name.strip_suffix(RING_SUFFIX).unwrap_or(&file_name);
+        let _ = std::fs::remove_file(dirs.lock_path(name));
+    }
+    Ok(())
 }
 
 
-/* Atomically read and erase the contents of the ipc object with *name*. Counts 
- * as a read.
+/* Path of the socket the server waits on for IpcBackend::notify_server().
  */
-pub fn consume(name: &str) -> Result<String> {
-    //#[cfg(debug_assertions)] println!("ipc consume: name: {}", name);
-    let lock = Locked::new(name)?;
-    let path = format!("{}{}",IPC_PATH, name);
-    if ! Path::new(&path).exists() {
-        return Ok(String::new());
-    }
-    let mut f = File::options().read(true).write(true).open(&path)?;    
-    let mut s = String::new();
-    f.seek(SeekFrom::Start(1))?;
-    f.read_to_string(&mut s)?;
-    std::fs::remove_file(&path)?;
-    let mut f_new = File::options().create(true).write(true).open(&path)?;
-    f_new.write_all(&[1 as u8])?;
-    lock.unlock()?;
-    Ok(s)
+pub fn wake_path() -> Result<String> {
+    Ok(format!("{}{}", ipc_path()?, WAKE_NAME))
 }
 
 
-/* Return true if the ipc object with *name* has been written to since the last 
- * read (or consume).
+/* Path of the socket a game waits on for IpcBackend::notify_game().
  */
-pub fn has_new(name: &str) -> Result<bool> {
-    //#[cfg(debug_assertions)] println!("has_new: {}", name);
-    let lock = Locked::new(name)?;
-    let path = format!("{}{}",IPC_PATH, name);
-    if ! Path::new(&path).exists() {
-        //#[cfg(debug_assertions)] println!("no file to check");
-	return Ok(false);
+pub fn game_wake_path() -> Result<String> {
+    Ok(format!("{}{}", ipc_path()?, GAME_WAKE_NAME))
+}
+
+
+/* Let the server know that an IPC object it reads has been written to. If the
+ * server isn't listening (or isn't running) it will still notice the write
+ * the next time it checks on its own, so failures are ignored.
+ */
+pub(crate) fn send_wake() {
+    if let Ok(path) = wake_path() {
+        send_wake_to(&path);
     }
-    let mut f = File::options().read(true).write(false).create(false).open(&path)?;    
-    if f.metadata()?.len() == 0 {
-	return Ok(false);
+}
+
+// and the game, for IpcBackend::notify_game()
+fn send_game_wake() {
+    if let Ok(path) = game_wake_path() {
+        send_wake_to(&path);
     }
-    let mut buf = [0 as u8];
-    f.read_exact(&mut buf)?;
-    lock.unlock()?;
-    Ok(buf[0] != 0)
 }
 
+#[cfg(unix)]
+fn send_wake_to(path: &str) {
+    use std::os::unix::net::UnixDatagram;
+    if let Ok(sock) = UnixDatagram::unbound() {
+        let _ = sock.set_nonblocking(true);
+        let _ = sock.send_to(&[1], path);
+    }
+}
+
+#[cfg(not(unix))]
+fn send_wake_to(_path: &str) {
+}
+
+
+// The game's end of notify_game(), bound on first use. Only one process can
+// listen on it (any others fall back to checking every WAIT_POLL_INTERVAL).
+#[cfg(unix)]
+static GAME_WAKE: Mutex<Option<Arc<std::os::unix::net::UnixDatagram>>> = Mutex::new(None);
+
+#[cfg(unix)]
+fn bind_game_wake() -> Option<Arc<std::os::unix::net::UnixDatagram>> {
+    use std::os::unix::net::UnixDatagram;
+    let mut guard = GAME_WAKE.lock().unwrap_or_else(|e| e.into_inner());
+    if guard.is_none() {
+        let path = game_wake_path().ok()?;
+        let sock = UnixDatagram::bind(&path).or_else(|_| {
+            // the socket file is either someone else's or left over from a
+            // game that exited; only take it over in the second case
+            let probe = UnixDatagram::unbound()?;
+            if probe.connect(&path).is_ok() {
+                return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse));
+            }
+            std::fs::remove_file(&path)?;
+            UnixDatagram::bind(&path)
+        });
+        *guard = sock.ok().map(Arc::new);
+    }
+    guard.clone()
+}
+
+#[cfg(unix)]
+fn wait_for_game_wake(timeout: Duration) {
+    match bind_game_wake() {
+        Some(sock) => wait_for_datagram(&sock, timeout),
+        None => std::thread::sleep(timeout.min(WAIT_POLL_INTERVAL)),
+    }
+}
+
+#[cfg(unix)]
+fn wait_for_datagram(sock: &std::os::unix::net::UnixDatagram, timeout: Duration) {
+    // a zero timeout means forever to set_read_timeout
+    let _ = sock.set_read_timeout(Some(timeout.max(Duration::from_millis(1))));
+    if sock.recv(&mut [0_u8; 16]).is_ok() {
+        // one wait covers every notification sent so far, so don't leave the
+        // rest to end later waits early
+        let _ = sock.set_nonblocking(true);
+        while sock.recv(&mut [0_u8; 16]).is_ok() {}
+        let _ = sock.set_nonblocking(false);
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_for_game_wake(timeout: Duration) {
+    std::thread::sleep(timeout.min(WAIT_POLL_INTERVAL));
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the server cleans up after itself without touching anybody else's
+    // files when it shares a directory
+    #[test]
+    fn purge_only_deletes_ipc_objects() {
+        let ipc_dir = tempfile::tempdir().unwrap();
+        let lock_dir = tempfile::tempdir().unwrap();
+        let dirs = config::Dirs {
+            ipc_dir: ipc_dir.path().to_string_lossy().into_owned(),
+            lock_dir: lock_dir.path().to_string_lossy().into_owned(),
+            namespace: String::new(),
+        };
+        let ours = ["cp_clients", "rpc_out", "events", "0123456789abcdef-0_in",
+                    "0123456789abcdef-12_out.ring", "wake", "ipc.sock"];
+        let theirs = ["notes.txt", "check_in", "sl_server", "cp_clients.bak", "abc-1_out"];
+        for name in ours.iter().chain(&theirs) {
+            std::fs::write(ipc_dir.path().join(name), b"x").unwrap();
+        }
+        std::fs::create_dir(ipc_dir.path().join("other")).unwrap();
+        std::fs::write(ipc_dir.path().join("other").join("cp_clients"), b"x").unwrap();
+        std::fs::write(dirs.lock_path("cp_clients"), b"").unwrap();
+        std::fs::write(dirs.lock_path("server"), b"").unwrap();
+        purge_in(&dirs).unwrap();
+        for name in ours {
+            assert!(!ipc_dir.path().join(name).exists(), "{} is still there", name);
+        }
+        for name in theirs {
+            assert!(ipc_dir.path().join(name).exists(), "{} was deleted", name);
+        }
+        assert!(ipc_dir.path().join("other").join("cp_clients").exists());
+        assert!(!Path::new(&dirs.lock_path("cp_clients")).exists());
+        assert!(Path::new(&dirs.lock_path("server")).exists());
+    }
 
+    // a game that was running when the server restarted (and deleted every
+    // ring) writes to the new ring, not the deleted one it had mapped
+    #[test]
+    fn remaps_rings_replaced_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ring_test_remap_out.ring");
+        let path = path.to_str().unwrap();
+        let name = "ring_test_remap_out";
+        let game = RingBackend::new();
+        game.ring_at(name, path).unwrap().write(b"old").unwrap();
+        std::fs::remove_file(path).unwrap();
+        let server = Ring::open(path, name, crate::ring::DEFAULT_CAPACITY).unwrap();
+        game.ring_at(name, path).unwrap().write(b"new").unwrap();
+        assert_eq!(server.consume().unwrap(), b"new");
+        // and keeps using it while it's there
+        let ring = game.ring_at(name, path).unwrap();
+        assert!(Arc::ptr_eq(&ring, &game.ring_at(name, path).unwrap()));
+    }
+
+    // a burst of notifications wakes one wait, not one wait each
+    #[cfg(unix)]
+    #[test]
+    fn one_wait_takes_every_pending_wake() {
+        use std::os::unix::net::UnixDatagram;
+        let (game, server) = UnixDatagram::pair().unwrap();
+        for _ in 0..3 {
+            server.send(&[0]).unwrap();
+        }
+        let timeout = Duration::from_millis(200);
+        let start = std::time::Instant::now();
+        wait_for_datagram(&game, timeout);
+        assert!(start.elapsed() < timeout);
+        let start = std::time::Instant::now();
+        wait_for_datagram(&game, timeout);
+        assert!(start.elapsed() >= timeout);
+    }
+}
@@ -0,0 +1,313 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+
+use crate::ipc::{IpcBackend, Result};
+
+//=================================== Notes ====================================
+/*
+IPC over a unix domain socket. The server keeps every IPC object in a backend
+of its own (a MemoryBackend) and games send it requests over the socket
+instead of touching files.
+
+request:  [len: u32 LE][op: u8][name len: u16 LE][name][data]
+response: [len: u32 LE][status: u8][data]
+
+len counts the bytes that follow it. A status other than STATUS_OK means data
+holds an error message. Each op does the same thing as the IpcBackend method
+of the same name on the server's backend.
+*/
+
+//================================= Constants ==================================
+const OP_WRITE: u8 = 1;
+const OP_READ: u8 = 2;
+const OP_CONSUME: u8 = 3;
+const OP_HAS_NEW: u8 = 4;
+//
+const STATUS_OK: u8 = 0;
+const STATUS_ERR: u8 = 1;
+//
+// sanity limit so a bad length can't make us allocate the world
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+//=================================== Frames ===================================
+fn write_frame(stream: &mut UnixStream, head: &[u8], data: &[u8]) -> Result<()> {
+    let len = (head.len() + data.len()) as u32;
+    let mut buf = Vec::with_capacity(4 + len as usize);
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(head);
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+fn read_frame(stream: &mut UnixStream) -> Result<Vec<u8>> {
+    let mut len_bytes = [0_u8; 4];
+    stream.read_exact(&mut len_bytes)?;
+    let len = u32::from_le_bytes(len_bytes) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(format!("frame of {} bytes is too long", len).into());
+    }
+    let mut buf = vec![0_u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+//==================================== Host ====================================
+// Out: the response (status, data) for a single request frame, calling wake
+//      after every write
+fn handle_request(backend: &dyn IpcBackend, frame: &[u8], wake: fn()) -> (u8, Vec<u8>) {
+    if frame.len() < 3 {
+        return (STATUS_ERR, b"request too short".to_vec());
+    }
+    let op = frame[0];
+    let name_len = u16::from_le_bytes([frame[1], frame[2]]) as usize;
+    if frame.len() < 3 + name_len {
+        return (STATUS_ERR, b"name runs past end of request".to_vec());
+    }
+    let name = match std::str::from_utf8(&frame[3..3+name_len]) {
+        Ok(name) => name,
+        Err(_) => return (STATUS_ERR, b"object name is not valid utf8".to_vec()),
+    };
+    let data = &frame[3+name_len..];
+    let result = match op {
+        OP_WRITE => backend.write(name, data).map(|_| {
+            // the server's main loop doesn't see writes to its own backend
+            wake();
+            vec![]
+        }),
+        OP_READ => backend.read(name),
+        OP_CONSUME => backend.consume(name),
+        OP_HAS_NEW => backend.has_new(name).map(|b| vec![b as u8]),
+        _ => Err(format!("unknown op {}", op).into()),
+    };
+    match result {
+        Ok(data) => (STATUS_OK, data),
+        Err(e) => (STATUS_ERR, e.to_string().into_bytes()),
+    }
+}
+
+fn serve_game(backend: Arc<dyn IpcBackend>, mut stream: UnixStream, wake: fn()) {
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            // the game disconnected
+            Err(_) => return,
+        };
+        let (status, data) = handle_request(backend.as_ref(), &frame, wake);
+        if let Err(e) = write_frame(&mut stream, &[status], &data) {
+            println!("Warning: failed to respond to game over ipc socket: {}", e);
+            return;
+        }
+    }
+}
+
+/* Start accepting games on the socket at *path*. Each game gets its own thread
+ * which works directly on *backend*.
+ */
+pub fn host(path: &str, backend: Arc<dyn IpcBackend>) -> Result<()> {
+    listen(path, backend, crate::ipc::send_wake)
+}
+
+fn listen(path: &str, backend: Arc<dyn IpcBackend>, wake: fn()) -> Result<()> {
+    // a socket file left over from a previous run would make bind fail
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let backend = backend.clone();
+                    std::thread::spawn(move || serve_game(backend, stream, wake));
+                }
+                Err(e) => {
+                    println!("Warning: failed to accept game on ipc socket: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+//=============================== SocketBackend ================================
+// The game's side: every call is a request to the server. The connection is
+// opened on first use and reopened after an error.
+pub struct SocketBackend {
+    path: String,
+    connection: Mutex<Option<UnixStream>>,
+}
+
+impl SocketBackend {
+    pub fn new(path: &str) -> Self {
+        SocketBackend {
+            path: path.to_string(),
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn request(&self, op: u8, name: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let mut guard = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        if guard.is_none() {
+            let stream = UnixStream::connect(&self.path).map_err(|e| {
+                format!("Could not connect to controlpad server at {}: {}",
+                        self.path, e)
+            })?;
+            *guard = Some(stream);
+        }
+        // unwrap because we just made sure there's a connection
+        let stream = guard.as_mut().unwrap();
+        let mut head = vec![op];
+        head.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        head.extend_from_slice(name.as_bytes());
+        let result = write_frame(stream, &head, data)
+            .and_then(|_| read_frame(stream));
+        let frame = match result {
+            Ok(frame) => frame,
+            Err(e) => {
+                *guard = None;
+                return Err(e);
+            }
+        };
+        if frame.is_empty() {
+            *guard = None;
+            return Err("empty response from controlpad server".into());
+        }
+        if frame[0] != STATUS_OK {
+            return Err(String::from_utf8_lossy(&frame[1..]).into_owned().into());
+        }
+        Ok(frame[1..].to_vec())
+    }
+}
+
+impl IpcBackend for SocketBackend {
+    fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.request(OP_WRITE, name, data)?;
+        Ok(())
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>> {
+        self.request(OP_READ, name, &[])
+    }
+
+    fn consume(&self, name: &str) -> Result<Vec<u8>> {
+        self.request(OP_CONSUME, name, &[])
+    }
+
+    fn has_new(&self, name: &str) -> Result<bool> {
+        Ok(self.request(OP_HAS_NEW, name, &[])?.first() == Some(&1))
+    }
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::ipc::MemoryBackend;
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_wake() {
+        WAKES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // a MemoryBackend that refuses to read objects nobody has written, so
+    // that there's an error to send back
+    #[derive(Default)]
+    struct Strict {
+        memory: MemoryBackend,
+        written: Mutex<HashSet<String>>,
+    }
+
+    impl Strict {
+        fn check(&self, name: &str) -> Result<()> {
+            if !self.written.lock().unwrap().contains(name) {
+                return Err(format!("no object named {}", name).into());
+            }
+            Ok(())
+        }
+    }
+
+    impl IpcBackend for Strict {
+        fn write(&self, name: &str, data: &[u8]) -> Result<()> {
+            self.written.lock().unwrap().insert(name.to_string());
+            self.memory.write(name, data)
+        }
+
+        fn read(&self, name: &str) -> Result<Vec<u8>> {
+            self.check(name)?;
+            self.memory.read(name)
+        }
+
+        fn consume(&self, name: &str) -> Result<Vec<u8>> {
+            self.check(name)?;
+            self.memory.consume(name)
+        }
+
+        fn has_new(&self, name: &str) -> Result<bool> {
+            self.memory.has_new(name)
+        }
+    }
+
+    #[test]
+    fn games_work_on_the_hosts_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ipc.sock");
+        let path = path.to_str().unwrap();
+        let server = Arc::new(Strict::default());
+        listen(path, server.clone(), count_wake).unwrap();
+        let game = SocketBackend::new(path);
+
+        game.write("rpc_out", b"hello ").unwrap();
+        game.write("rpc_out", &[0, 0xff]).unwrap();
+        assert_eq!(WAKES.load(Ordering::SeqCst), 2);
+        assert!(server.has_new("rpc_out").unwrap());
+        assert_eq!(server.consume("rpc_out").unwrap(), b"hello \0\xff");
+
+        server.write("cp_clients", b"clients").unwrap();
+        assert!(game.has_new("cp_clients").unwrap());
+        assert_eq!(game.read("cp_clients").unwrap(), b"clients");
+        assert!(!game.has_new("cp_clients").unwrap());
+        assert_eq!(game.consume("cp_clients").unwrap(), b"clients");
+        assert!(game.read("cp_clients").unwrap().is_empty());
+
+        let e = game.read("missing").unwrap_err();
+        assert_eq!(e.to_string(), "no object named missing");
+        // an error reply doesn't cost the game its connection
+        assert_eq!(game.read("cp_clients").unwrap(), b"");
+        assert_eq!(WAKES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let memory = MemoryBackend::new();
+        for frame in [&[OP_READ][..], &[OP_READ, 5, 0, b'a'], &[OP_READ, 1, 0, 0xff], &[9, 0, 0]] {
+            let (status, message) = handle_request(&memory, frame, count_wake);
+            assert_eq!(status, STATUS_ERR, "{:?}", frame);
+            assert!(!message.is_empty());
+        }
+    }
+}
+
+//==================================<===|===>===================================
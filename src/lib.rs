@@ -17,71 +17,357 @@
  * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
  */
 
-mod ipc;
-mod systemlock;
-use std::str;
-type GenErr = Box<dyn std::error::Error>;
-type Result<T> = std::result::Result<T, GenErr>;
+#[cfg(feature = "async")]
+mod async_api;
+#[doc(hidden)]
+pub mod capi;
+mod client;
+#[doc(hidden)]
+pub mod clock;
+pub mod config;
+#[doc(hidden)]
+pub mod controller;
+mod error;
+#[doc(hidden)]
+pub mod event;
+#[doc(hidden)]
+pub mod frame;
+pub mod ipc;
+#[doc(hidden)]
+pub mod multicast;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(unix)]
+#[doc(hidden)]
+pub mod ipcsock;
+#[doc(hidden)]
+pub mod ring;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod systemlock;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use ipc::IpcBackend;
+#[cfg(feature = "async")]
+pub use async_api::{AsyncClient, EventStream};
+pub use client::{ClientHandle, ClientState};
+pub use error::ControlpadError;
+pub use event::Event;
+pub use frame::{Message, Timing};
+pub type Result<T> = std::result::Result<T, ControlpadError>;
 
+// the backend used by every function below; chosen from the environment on
+// first use unless set_backend() was called
+static BACKEND: RwLock<Option<Arc<dyn IpcBackend>>> = RwLock::new(None);
 
-pub type ClientHandle = String;
+fn backend() -> Arc<dyn IpcBackend> {
+    if let Some(backend) = BACKEND.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        return backend.clone();
+    }
+    let mut guard = BACKEND.write().unwrap_or_else(|e| e.into_inner());
+    guard.get_or_insert_with(ipc::game_backend).clone()
+}
+
+/// Use backend for all communication with the control pad server instead of
+/// the one selected by the CONTROLPAD_IPC environment variable (e.g. an
+/// ipc::MemoryBackend shared with a server in the same process for tests).
+/// What we knew about the old server's clients and events is forgotten.
+pub fn set_backend(backend: Arc<dyn IpcBackend>) {
+    *BACKEND.write().unwrap_or_else(|e| e.into_inner()) = Some(backend);
+    *KNOWN_CLIENTS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    EVENTS_SUBSCRIBED.store(false, Ordering::SeqCst);
+}
+
+
+// the clients from the latest call to get_client_handles (kept up to date by
+// poll_events), None until then
+static KNOWN_CLIENTS: Mutex<Option<Vec<ClientHandle>>> = Mutex::new(None);
+
+// whether we've asked the server to send us events (see event.rs)
+static EVENTS_SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+fn check_server(backend: &dyn IpcBackend) -> Result<()> {
+    // e.g. CONTROLPAD_NAMESPACE is invalid
+    config::dirs().map_err(ControlpadError::Io)?;
+    if !backend.server_available() {
+        return Err(ControlpadError::ServerUnavailable);
+    }
+    Ok(())
+}
+
+fn check_client(client: &ClientHandle) -> Result<()> {
+    let known = KNOWN_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+    match known.as_ref() {
+        Some(clients) if !clients.contains(client) => {
+            Err(ControlpadError::ClientNotFound(client.clone()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// an IPC operation that fails is usually failing because the server is gone
+fn ipc_error(backend: &dyn IpcBackend, e: ipc::Error) -> ControlpadError {
+    if backend.server_available() {
+        ControlpadError::Io(e)
+    } else {
+        ControlpadError::ServerUnavailable
+    }
+}
 
 /// Returns true if and only if a client has been added, dropped, or refreshed
 /// since the last call to get_client_handles
 pub fn clients_changed() -> Result<bool> {
-    ipc::has_new("cp_clients").or_else(|e| {
-        Err(format!("Failed to check has_new: {}", e).into())
-    })
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    backend.has_new("cp_clients").map_err(|e| ipc_error(backend.as_ref(), e))
 }
 
 /// Returns a vector of ClientHandles corresponding to the control pad clients
-/// currently connected to the local control pad server
+/// currently connected to (or suspended by) the local control pad server, in
+/// the order they joined
 pub fn get_client_handles() -> Result<Vec<ClientHandle>> {
-    let mut ret: Vec<ClientHandle> = Vec::new();
-    let clients_string = ipc::read("cp_clients").or_else(|e| {
-        Err::<_, GenErr>(format!("Failed to read: {}", e).into())
-    })?;
-    let mut parts = clients_string.split(str::from_utf8(&[0])?);
-    
-    while let Some(p) = parts.next() {
-	// don't take whatever is after the last null byte because nothing should be
-	// past the last null byte and we don't want to add an empty string
-	if p.len() != 0 {
-	    ret.push(String::from(p));
-	}
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    let clients = backend.read("cp_clients")
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    let malformed = |e: ipc::Error| ControlpadError::MalformedData(e.to_string());
+    let mut handles = Vec::new();
+    for msg in frame::decode(&clients).map_err(malformed)? {
+        if let Message::Bytes(record) = msg {
+            handles.push(ClientHandle::from_record(&record).map_err(malformed)?);
+        }
     }
-    Ok(ret)
+    *KNOWN_CLIENTS.lock().unwrap_or_else(|e| e.into_inner()) = Some(handles.clone());
+    Ok(handles)
 }
 
-/// Send an atomic message to the specified control pad client
+/// The round trip time the server last measured to client, or None if it
+/// hasn't measured one yet
+pub fn latency(client: &ClientHandle) -> Result<Option<Duration>> {
+    check_client(client)?;
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    let data = backend.read(stats::STATS_OBJECT)
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    let rtts = stats::decode(&data)
+        .map_err(|e| ControlpadError::MalformedData(e.to_string()))?;
+    Ok(rtts.into_iter().find(|(id, _)| id == client.id()).map(|(_, rtt)| rtt))
+}
+
+/// Send an atomic text message to the specified control pad client
 pub fn send_message(client: &ClientHandle, msg: &str) -> Result<()> {
-    let ipc_name = client.to_string() + "_out";
-    //println!("sent {}", msg);
-    let delin_msg = msg.to_string() + str::from_utf8(&[0])?;
-    ipc::write(&ipc_name, &delin_msg).or_else(|e| {
-        Err::<_, GenErr>(format!("Failed to write: {}", e).into())
-    })?;
+    send(client, &Message::Text(msg.to_string()))
+}
+
+/// Send an atomic binary message to the specified control pad client
+pub fn send_bytes(client: &ClientHandle, bytes: &[u8]) -> Result<()> {
+    send(client, &Message::Bytes(bytes.to_vec()))
+}
+
+fn send(client: &ClientHandle, msg: &Message) -> Result<()> {
+    check_client(client)?;
+    let ipc_name = client.id().to_string() + "_out";
+    let backend = backend();
+    // with files the write would succeed with nobody to read it
+    check_server(backend.as_ref())?;
+    backend.write(&ipc_name, &frame::encode(std::slice::from_ref(msg)))
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    backend.notify_server();
     Ok(())
 }
 
-/// Returns a vector of all messages that have been received from the
-/// specified control pad client since the last call to this function for that
-/// client
-pub fn get_messages(client: &ClientHandle) -> Result<Vec<String>> {
-    let mut ret: Vec<String> = Vec::new();
-    let ipc_name = client.to_string() + "_in";
-    let msgs_string = ipc::consume(&ipc_name).or_else(|e| {
-        Err::<_, GenErr>(format!("Failed to consume: {}", e).into())
-    })?;
-    if msgs_string.len() == 0 {
-	return Ok(vec![]);
+/// Send an atomic text message to every connected client with a single write
+/// (the server does the fanning out). Relative to messages sent with
+/// send_message the order isn't guaranteed.
+pub fn broadcast(msg: &str) -> Result<()> {
+    multicast(None, &Message::Text(msg.to_string()))
+}
+
+/// Send an atomic binary message to every connected client (see broadcast)
+pub fn broadcast_bytes(bytes: &[u8]) -> Result<()> {
+    multicast(None, &Message::Bytes(bytes.to_vec()))
+}
+
+/// Send an atomic text message to each of clients with a single write (see
+/// broadcast)
+pub fn send_to(clients: &[ClientHandle], msg: &str) -> Result<()> {
+    multicast(Some(clients), &Message::Text(msg.to_string()))
+}
+
+/// Send an atomic binary message to each of clients (see broadcast)
+pub fn send_bytes_to(clients: &[ClientHandle], bytes: &[u8]) -> Result<()> {
+    multicast(Some(clients), &Message::Bytes(bytes.to_vec()))
+}
+
+fn multicast(clients: Option<&[ClientHandle]>, msg: &Message) -> Result<()> {
+    let ids = match clients {
+        Some(clients) => {
+            for client in clients {
+                check_client(client)?;
+            }
+            Some(clients.iter().map(|c| c.id()).collect::<Vec<&str>>())
+        }
+        None => None,
+    };
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    backend.write(multicast::MULTICAST_OBJECT, &multicast::encode(ids.as_deref(), msg))
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    backend.notify_server();
+    Ok(())
+}
+
+/// Tell every client to show the controller UI in bundle, a directory holding
+/// its index.html and other files (which the server serves) or the URL of a
+/// page. The default controller page swaps a directory in without reloading
+/// and loads a URL in place of itself. None goes back to the server's default
+/// page, as does the console switching games.
+pub fn set_controller(bundle: Option<&str>) -> Result<()> {
+    let bundle = match bundle {
+        None => String::new(),
+        Some(url) if controller::is_url(url) => url.to_string(),
+        // the server doesn't share our working directory
+        Some(dir) => {
+            let path = std::fs::canonicalize(dir)
+                .map_err(|e| ControlpadError::Io(format!("{}: {}", dir, e).into()))?;
+            if !path.is_dir() {
+                return Err(ControlpadError::Io(
+                    format!("{} is not a directory", dir).into()));
+            }
+            path.to_string_lossy().into_owned()
+        }
+    };
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    let rpc = frame::encode(&[Message::Text(controller::SET_CONTROLLER.to_string() + &bundle)]);
+    backend.write("rpc_out", &rpc).map_err(|e| ipc_error(backend.as_ref(), e))?;
+    backend.notify_server();
+    Ok(())
+}
+
+/// Returns a vector of all messages (text or binary) that have been received
+/// from the specified control pad client since the last call to this function
+/// for that client
+pub fn get_messages(client: &ClientHandle) -> Result<Vec<Message>> {
+    check_client(client)?;
+    let ipc_name = client.id().to_string() + "_in";
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    let msgs = backend.consume(&ipc_name)
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    frame::decode(&msgs).map_err(|e| ControlpadError::MalformedData(e.to_string()))
+}
+
+/// Like get_messages but with when each message was sent and received (see
+/// Timing)
+pub fn get_timed_messages(client: &ClientHandle) -> Result<Vec<(Message, Timing)>> {
+    check_client(client)?;
+    let ipc_name = client.id().to_string() + "_in";
+    let backend = backend();
+    check_server(backend.as_ref())?;
+    let msgs = backend.consume(&ipc_name)
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    let msgs = frame::decode_timed(&msgs)
+        .map_err(|e| ControlpadError::MalformedData(e.to_string()))?;
+    // written by a server that doesn't time messages: it was just now
+    // as far as we know
+    let now = SystemTime::now();
+    Ok(msgs.into_iter()
+        .map(|(msg, timing)| (msg, timing.unwrap_or(Timing::at(now))))
+        .collect())
+}
+
+/// Returns everything that happened to the control pad clients since the last
+/// call, in the order it happened: clients joining, leaving, being renamed,
+/// disconnecting and reconnecting, and their messages.
+///
+/// The first call asks the server to start sending events, so it returns
+/// nothing, and the next one starts with a Joined for every client already
+/// connected. From then on messages from clients only come through here
+/// (get_messages won't return them) and clients_changed stays meaningful.
+pub fn poll_events() -> Result<Vec<Event>> {
+    let backend = backend();
+    if !EVENTS_SUBSCRIBED.swap(true, Ordering::SeqCst) {
+        let subscribe = frame::encode(&[Message::Text(event::SUBSCRIBE.to_string())]);
+        let result = check_server(backend.as_ref()).and_then(|_| {
+            backend.write("rpc_out", &subscribe)
+                .map_err(|e| ipc_error(backend.as_ref(), e))
+        });
+        if let Err(e) = result {
+            EVENTS_SUBSCRIBED.store(false, Ordering::SeqCst);
+            return Err(e);
+        }
+        backend.notify_server();
     }
-    //println!("{}", msgs_string.replace(str::from_utf8(&[0])?, "0"));
-    let mut parts = msgs_string.split(str::from_utf8(&[0])?).collect::<Vec<&str>>();
-    parts.pop(); // there will be nothing after last null byte
-    for p in &parts {
-	//println!("got {}", p);
-	ret.push(String::from(*p));
+    let data = backend.consume(event::EVENTS_OBJECT)
+        .map_err(|e| ipc_error(backend.as_ref(), e))?;
+    let events = event::decode(&data)
+        .map_err(|e| ControlpadError::MalformedData(e.to_string()))?;
+    let mut ret = Vec::with_capacity(events.len());
+    let mut known = KNOWN_CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let known = known.get_or_insert_with(Vec::new);
+    for e in events {
+        match &e {
+            Event::Joined(client) if !known.contains(client) => {
+                known.push(client.clone());
+            }
+            Event::Left(client) => known.retain(|c| c != client),
+            // the server stopped sending events (e.g. for a reload) so ask
+            // again next time
+            Event::Unsubscribed => {
+                EVENTS_SUBSCRIBED.store(false, Ordering::SeqCst);
+                continue;
+            }
+            _ => {}
+        }
+        ret.push(e);
     }
     Ok(ret)
 }
+
+// wait for the server until ready() or timeout
+fn wait_until(timeout: Duration,
+              mut ready: impl FnMut(&dyn IpcBackend) -> Result<bool>) -> Result<bool> {
+    let backend = backend();
+    let deadline = Instant::now() + timeout;
+    loop {
+        if ready(backend.as_ref())? {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        backend.wait_for_server(deadline - now);
+    }
+}
+
+/// Blocks until clients_changed would return true or timeout passes. Returns
+/// whether it would.
+pub fn wait_for_clients_changed(timeout: Duration) -> Result<bool> {
+    wait_until(timeout, |_| clients_changed())
+}
+
+/// Blocks until there's a message waiting from one of the clients from the
+/// latest get_client_handles (or, once subscribed, an event for poll_events)
+/// or timeout passes. Returns whether there is one.
+pub fn wait_for_messages(timeout: Duration) -> Result<bool> {
+    wait_until(timeout, |backend| {
+        let pending = |name: &str| -> Result<bool> {
+            let data = backend.read(name).map_err(|e| ipc_error(backend, e))?;
+            Ok(!data.is_empty())
+        };
+        if EVENTS_SUBSCRIBED.load(Ordering::SeqCst) {
+            return pending(event::EVENTS_OBJECT);
+        }
+        let known = KNOWN_CLIENTS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for client in known.unwrap_or_default() {
+            if pending(&(client.id().to_string() + "_in"))? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })
+}
@@ -0,0 +1,134 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use crate::frame::{self, Message};
+use crate::ipc::Result;
+
+//=================================== Notes ====================================
+/*
+Messages the game sends to more than one client go through a single IPC object
+(MULTICAST_OBJECT) instead of every client's <id>_out, and the server fans them
+out. Each one is a Bytes frame (see frame.rs) holding:
+
+[target count: u32 LE][id len: u16 LE][id]...[the message's frame]
+
+where a target count of EVERYONE means every client (and no ids follow).
+*/
+
+//================================= Constants ==================================
+pub const MULTICAST_OBJECT: &str = "multicast_out";
+//
+const EVERYONE: u32 = u32::MAX;
+
+//================================= Multicast ==================================
+/* The frame for sending *msg* to the clients with *ids* (all of them if None).
+ */
+pub fn encode(ids: Option<&[&str]>, msg: &Message) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match ids {
+        Some(ids) => {
+            payload.extend_from_slice(&(ids.len() as u32).to_le_bytes());
+            for id in ids {
+                payload.extend_from_slice(&(id.len() as u16).to_le_bytes());
+                payload.extend_from_slice(id.as_bytes());
+            }
+        }
+        None => payload.extend_from_slice(&EVERYONE.to_le_bytes()),
+    }
+    frame::encode_into(&mut payload, msg);
+    frame::encode(&[Message::Bytes(payload)])
+}
+
+/* Split the contents of the multicast object back into (ids, message) pairs,
+ * with None for messages to everyone.
+ */
+pub fn decode(data: &[u8]) -> Result<Vec<(Option<Vec<String>>, Message)>> {
+    let mut ret = Vec::new();
+    for msg in frame::decode(data)? {
+        let Message::Bytes(payload) = msg else { continue };
+        let truncated = || format!("multicast of {} bytes is truncated", payload.len());
+        let count_bytes = payload.get(..4).ok_or_else(truncated)?;
+        let count = u32::from_le_bytes([count_bytes[0], count_bytes[1],
+                                        count_bytes[2], count_bytes[3]]);
+        let mut rest = &payload[4..];
+        let ids = if count == EVERYONE {
+            None
+        } else {
+            // every id takes at least its two length bytes, so a corrupt
+            // count can't make us allocate more than the payload's worth
+            let mut ids = Vec::with_capacity((count as usize).min(rest.len() / 2));
+            for _ in 0..count {
+                let len_bytes = rest.get(..2).ok_or_else(truncated)?;
+                let len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+                let id = rest.get(2..2 + len).ok_or_else(truncated)?;
+                ids.push(String::from_utf8(id.to_vec())?);
+                rest = &rest[2 + len..];
+            }
+            Some(ids)
+        };
+        let msg = frame::decode(rest)?.pop().ok_or_else(truncated)?;
+        ret.push((ids, msg));
+    }
+    Ok(ret)
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_targets_and_messages() {
+        let text = Message::Text("to some".to_string());
+        let bytes = Message::Bytes(vec![0, 0xff, 0]);
+        let mut data = encode(Some(&["0a9574c70a06d95f-3", "", "0a9574c70a06d95f-12"]), &text);
+        data.extend(encode(None, &bytes));
+        data.extend(encode(Some(&[]), &bytes));
+        assert_eq!(decode(&data).unwrap(), vec![
+            (Some(vec!["0a9574c70a06d95f-3".to_string(), String::new(),
+                       "0a9574c70a06d95f-12".to_string()]), text),
+            (None, bytes.clone()),
+            (Some(vec![]), bytes),
+        ]);
+    }
+
+    #[test]
+    fn rejects_truncated_multicasts() {
+        let hi = Message::Text("hi".to_string());
+        let data = encode(Some(&["0a9574c70a06d95f-3"]), &hi);
+        frame::tests::assert_rejects_truncations(&data, decode);
+        frame::tests::assert_rejects_truncations(&encode(None, &hi), decode);
+        // a count far beyond the ids that follow
+        let mut inflated = match frame::decode(&data).unwrap().pop() {
+            Some(Message::Bytes(payload)) => payload,
+            other => panic!("expected a Bytes frame, got {:?}", other),
+        };
+        inflated[..4].copy_from_slice(&(EVERYONE - 1).to_le_bytes());
+        assert!(decode(&frame::encode(&[Message::Bytes(inflated)])).is_err());
+        // to everyone, but with the ids of a targeted multicast where the
+        // message should be
+        let mut everyone = EVERYONE.to_le_bytes().to_vec();
+        everyone.extend_from_slice(&18u16.to_le_bytes());
+        everyone.extend_from_slice(b"0a9574c70a06d95f-3");
+        assert!(decode(&frame::encode(&[Message::Bytes(everyone)])).is_err());
+    }
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,175 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::time::{Duration, UNIX_EPOCH};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyString};
+use crate::{ClientHandle, ClientState, Message};
+
+//=================================== Notes ====================================
+/*
+The controlpads Python extension module (enabled by the "python" feature). It
+wraps the functions in lib.rs so Python games talk to the server through the
+same IPC code as Rust and C ones. Build it with
+
+    PYO3_BUILD_EXTENSION_MODULE=1 cargo build --release --features python
+
+and copy target/release/libcontrolpads.so to controlpads.so somewhere on the
+Python path (see tests/python).
+
+Messages are str for text and bytes for binary in both directions.
+
+Every call does IPC that can block (on a lock file, the server's socket or,
+for the wait_for_ functions, the server itself), so each one lets go of the
+GIL while it does so that the game's other Python threads keep running.
+*/
+
+//=================================== Types ====================================
+create_exception!(controlpads, ControlpadError, PyException,
+                  "A controlpads call failed (see ControlpadError in the Rust API)");
+
+impl From<crate::ControlpadError> for PyErr {
+    fn from(e: crate::ControlpadError) -> PyErr {
+        ControlpadError::new_err(e.to_string())
+    }
+}
+
+/// A control pad client (see ClientHandle in the Rust API)
+#[pyclass(module = "controlpads", name = "ClientHandle", frozen, eq, hash)]
+#[derive(PartialEq, Eq, Hash)]
+struct PyClientHandle(ClientHandle);
+
+#[pymethods]
+impl PyClientHandle {
+    #[getter]
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    #[getter]
+    fn index(&self) -> u64 {
+        self.0.index()
+    }
+
+    /// When the client joined, in seconds since the epoch (like time.time())
+    #[getter]
+    fn joined_at(&self) -> f64 {
+        self.0.joined_at().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+    }
+
+    /// "connected" or "suspended"
+    #[getter]
+    fn state(&self) -> &'static str {
+        match self.0.state() {
+            ClientState::Connected => "connected",
+            ClientState::Suspended => "suspended",
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<ClientHandle {}>", self.0)
+    }
+}
+
+// a message from Python: str or bytes
+#[derive(FromPyObject)]
+enum Outgoing {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+//================================= Functions ==================================
+/// True if and only if a client has been added, dropped, or refreshed since
+/// the last call to get_client_handles
+#[pyfunction]
+fn clients_changed(py: Python<'_>) -> PyResult<bool> {
+    Ok(py.detach(crate::clients_changed)?)
+}
+
+/// The clients currently connected to (or suspended by) the local control pad
+/// server, in the order they joined
+#[pyfunction]
+fn get_client_handles(py: Python<'_>) -> PyResult<Vec<PyClientHandle>> {
+    let clients = py.detach(crate::get_client_handles)?;
+    Ok(clients.into_iter().map(PyClientHandle).collect())
+}
+
+/// Send msg (str or bytes) to client as one atomic message
+#[pyfunction]
+fn send_message(py: Python<'_>, client: &PyClientHandle, msg: Outgoing) -> PyResult<()> {
+    py.detach(|| match msg {
+        Outgoing::Text(text) => crate::send_message(&client.0, &text),
+        Outgoing::Bytes(bytes) => crate::send_bytes(&client.0, &bytes),
+    })?;
+    Ok(())
+}
+
+/// The messages (str or bytes) received from client since the last call for
+/// that client
+#[pyfunction]
+fn get_messages(py: Python<'_>, client: &PyClientHandle) -> PyResult<Vec<Py<PyAny>>> {
+    let msgs = py.detach(|| crate::get_messages(&client.0))?;
+    Ok(msgs.into_iter().map(|m| match m {
+        Message::Text(text) => PyString::new(py, &text).into_any().unbind(),
+        Message::Bytes(bytes) => PyBytes::new(py, &bytes).into_any().unbind(),
+    }).collect())
+}
+
+// a timeout in seconds from Python
+fn duration(seconds: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(seconds)
+        .map_err(|e| PyValueError::new_err(format!("bad timeout {}: {}", seconds, e)))
+}
+
+/// Block until clients_changed would return True or timeout seconds pass.
+/// Returns whether it would.
+#[pyfunction]
+fn wait_for_clients_changed(py: Python<'_>, timeout: f64) -> PyResult<bool> {
+    let timeout = duration(timeout)?;
+    Ok(py.detach(|| crate::wait_for_clients_changed(timeout))?)
+}
+
+/// Block until there's a message waiting from one of the clients from the
+/// latest get_client_handles or timeout seconds pass. Returns whether there
+/// is one.
+#[pyfunction]
+fn wait_for_messages(py: Python<'_>, timeout: f64) -> PyResult<bool> {
+    let timeout = duration(timeout)?;
+    Ok(py.detach(|| crate::wait_for_messages(timeout))?)
+}
+
+//=================================== Module ===================================
+#[pymodule]
+#[pyo3(name = "controlpads")]
+fn controlpads_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("ControlpadError", m.py().get_type::<ControlpadError>())?;
+    m.add_class::<PyClientHandle>()?;
+    m.add_function(wrap_pyfunction!(clients_changed, m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_handles, m)?)?;
+    m.add_function(wrap_pyfunction!(send_message, m)?)?;
+    m.add_function(wrap_pyfunction!(get_messages, m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_clients_changed, m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_messages, m)?)?;
+    Ok(())
+}
+
+//==================================<===|===>===================================
@@ -0,0 +1,372 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::fs::{File, Metadata};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use memmap2::MmapMut;
+
+use crate::systemlock::Locked;
+use crate::ipc::Result;
+
+//=================================== Notes ====================================
+/*
+A single-producer single-consumer ring buffer in a memory-mapped file. Each
+client direction gets its own ring (the server produces <id>_in and the game
+consumes it, the game produces <id>_out and the server consumes it) so neither
+side ever waits on a lock after the ring has been created.
+
+layout:
+  [0..8)      magic
+  [8..16)     capacity of the data region in bytes
+  [64..72)    head: total bytes ever consumed   (only the consumer stores it)
+  [128..136)  tail: total bytes ever produced   (only the producer stores it)
+  [192..)     data
+
+head and tail only grow; a position maps into the data region modulo the
+capacity. They come from a file anything could have written (e.g. a ring left
+over from another version), so they are checked before every use: tail is
+never behind head or more than the capacity ahead of it in a ring we wrote. The producer publishes bytes by storing tail with Release after
+copying them in, and the consumer frees them by storing head with Release
+after copying them out, so each side sees the other's bytes complete.
+
+That only works with one producer and one consumer, but a Ring is shared by
+every thread of the process that mapped it (e.g. a game sending to the same
+client from two threads), so each side takes a lock of its own first. The
+locks are in-process only; the other side never waits on them.
+*/
+
+//================================= Constants ==================================
+const MAGIC: u64 = 0x4750_5249_4e47_0001; // "GPRING" v1
+const MAGIC_OFFSET: usize = 0;
+const CAPACITY_OFFSET: usize = 8;
+const HEAD_OFFSET: usize = 64;
+const TAIL_OFFSET: usize = 128;
+const DATA_OFFSET: usize = 192;
+//
+pub const DEFAULT_CAPACITY: usize = 256 * 1024;
+
+//==================================== Ring ====================================
+pub struct Ring {
+    // every access to the mapping goes through base (taken from the map
+    // once, mutably), never through map itself
+    _map: MmapMut,
+    base: *mut u8,
+    capacity: usize,
+    // which file was mapped (see is_at)
+    file_id: FileId,
+    // held while writing / while consuming (see Notes)
+    producer: Mutex<()>,
+    consumer: Mutex<()>,
+}
+
+impl Ring {
+    /* Map the ring at *path*, creating it with *capacity* bytes of data if it
+     * doesn't exist yet. *lock_name* guards creation against the other side
+     * doing the same thing at the same time.
+     */
+    pub fn open(path: &str, lock_name: &str, capacity: usize) -> Result<Self> {
+        if capacity == 0 {
+            return Err("a ring buffer needs room for at least one byte".into());
+        }
+        let lock = Locked::new(lock_name)?;
+        let file = File::options().read(true).write(true).create(true)
+            .truncate(false).open(path)?;
+        let fresh = file.metadata()?.len() == 0;
+        if fresh {
+            file.set_len((DATA_OFFSET + capacity) as u64)?;
+        }
+        // safety: the file is only ever resized here, before anybody maps it
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        if fresh {
+            map[CAPACITY_OFFSET..CAPACITY_OFFSET+8]
+                .copy_from_slice(&(capacity as u64).to_le_bytes());
+            map[MAGIC_OFFSET..MAGIC_OFFSET+8].copy_from_slice(&MAGIC.to_le_bytes());
+            map.flush()?;
+        }
+        lock.unlock()?;
+        let read_u64 = |off: usize| {
+            let mut b = [0_u8; 8];
+            b.copy_from_slice(&map[off..off+8]);
+            u64::from_le_bytes(b)
+        };
+        if read_u64(MAGIC_OFFSET) != MAGIC {
+            return Err(format!("{} is not a ring buffer", path).into());
+        }
+        let capacity = read_u64(CAPACITY_OFFSET) as usize;
+        if capacity == 0 {
+            return Err(format!("{} has no room for data", path).into());
+        }
+        if map.len() < DATA_OFFSET + capacity {
+            return Err(format!("{} is shorter than its capacity", path).into());
+        }
+        let file_id = FileId::of(&file.metadata()?);
+        let base = map.as_mut_ptr();
+        Ok(Ring {
+            _map: map,
+            base,
+            capacity,
+            file_id,
+            producer: Mutex::new(()),
+            consumer: Mutex::new(()),
+        })
+    }
+
+    /* Whether *path* is still the file this ring mapped (rather than gone, or
+     * deleted and created again, as the server does to every ring when it
+     * starts).
+     */
+    pub fn is_at(&self, path: &str) -> bool {
+        std::fs::metadata(path).is_ok_and(|m| FileId::of(&m) == self.file_id)
+    }
+
+    fn counter(&self, offset: usize) -> &AtomicU64 {
+        // safety: offset is 8 byte aligned within the (page aligned) mapping
+        // and the counters are only ever accessed atomically
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    fn data_ptr(&self) -> *mut u8 {
+        // safety: DATA_OFFSET is within the mapping
+        unsafe { self.base.add(DATA_OFFSET) }
+    }
+
+    // the number of bytes between head and tail, if they make sense
+    fn used(&self, head: u64, tail: u64) -> Result<usize> {
+        match tail.checked_sub(head) {
+            Some(used) if used <= self.capacity as u64 => Ok(used as usize),
+            _ => Err(format!("ring buffer is corrupt (head {}, tail {}, capacity {})",
+                             head, tail, self.capacity).into()),
+        }
+    }
+
+    /* Producer side: append *data*. Fails without writing anything if the
+     * consumer has fallen too far behind for it to fit.
+     */
+    pub fn write(&self, data: &[u8]) -> Result<()> {
+        let _producer = self.producer.lock().unwrap_or_else(|e| e.into_inner());
+        let head = self.counter(HEAD_OFFSET).load(Ordering::Acquire);
+        let tail = self.counter(TAIL_OFFSET).load(Ordering::Relaxed);
+        let used = self.used(head, tail)?;
+        if data.len() > self.capacity - used {
+            return Err(format!("ring buffer full ({} of {} bytes used, {} more \
+                                requested)", used, self.capacity, data.len()).into());
+        }
+        let start = (tail % self.capacity as u64) as usize;
+        let first = data.len().min(self.capacity - start);
+        // safety: both ranges are inside the data region and the consumer
+        // won't touch bytes past tail until we publish them below
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), self.data_ptr().add(start), first);
+            std::ptr::copy_nonoverlapping(data.as_ptr().add(first), self.data_ptr(),
+                                          data.len() - first);
+        }
+        self.counter(TAIL_OFFSET).store(tail + data.len() as u64, Ordering::Release);
+        Ok(())
+    }
+
+    // copy out everything between head and tail
+    fn pending(&self) -> Result<(Vec<u8>, u64)> {
+        let tail = self.counter(TAIL_OFFSET).load(Ordering::Acquire);
+        let head = self.counter(HEAD_OFFSET).load(Ordering::Relaxed);
+        let len = self.used(head, tail)?;
+        let start = (head % self.capacity as u64) as usize;
+        let first = len.min(self.capacity - start);
+        let mut out = vec![0_u8; len];
+        // safety: both ranges are inside the data region and the producer
+        // won't overwrite them until we move head past them
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.data_ptr().add(start), out.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(self.data_ptr(), out.as_mut_ptr().add(first),
+                                          len - first);
+        }
+        Ok((out, tail))
+    }
+
+    /* Consumer side: take everything written since the last consume.
+     */
+    pub fn consume(&self) -> Result<Vec<u8>> {
+        let _consumer = self.consumer.lock().unwrap_or_else(|e| e.into_inner());
+        let (out, tail) = self.pending()?;
+        self.counter(HEAD_OFFSET).store(tail, Ordering::Release);
+        Ok(out)
+    }
+
+    /* Consumer side: everything written since the last consume, left in place.
+     */
+    pub fn peek(&self) -> Result<Vec<u8>> {
+        let _consumer = self.consumer.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self.pending()?.0)
+    }
+
+    /* True if there is anything to consume.
+     */
+    pub fn has_new(&self) -> bool {
+        let tail = self.counter(TAIL_OFFSET).load(Ordering::Acquire);
+        let head = self.counter(HEAD_OFFSET).load(Ordering::Acquire);
+        tail != head
+    }
+}
+
+// safety: base points into _map, which lives as long as the Ring, and the
+// mapping is only touched through atomics and by whoever holds the producer
+// or consumer lock (see Notes)
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+//=================================== FileId ===================================
+// enough to tell two files at the same path apart
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct FileId {
+    #[cfg(unix)]
+    dev: u64,
+    #[cfg(unix)]
+    ino: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    fn of(metadata: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        FileId { dev: metadata.dev(), ino: metadata.ino() }
+    }
+
+    // a file that's mapped can't be deleted on Windows, so any file at the
+    // same path is the same one
+    #[cfg(not(unix))]
+    fn of(_metadata: &Metadata) -> Self {
+        FileId {}
+    }
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // a fresh ring with capacity bytes of data in dir (kept alive by the
+    // caller), named after the test so tests don't share lock files
+    fn ring(dir: &tempfile::TempDir, name: &str, capacity: usize) -> Ring {
+        let path = dir.path().join(name);
+        Ring::open(path.to_str().unwrap(), &format!("ring_test_{}", name), capacity).unwrap()
+    }
+
+    #[test]
+    fn peeks_and_consumes() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = ring(&dir, "peek", 64);
+        assert!(!ring.has_new());
+        ring.write(b"hello ").unwrap();
+        ring.write(b"phone").unwrap();
+        assert!(ring.has_new());
+        assert_eq!(ring.peek().unwrap(), b"hello phone");
+        // peeking leaves it there
+        assert_eq!(ring.peek().unwrap(), b"hello phone");
+        assert_eq!(ring.consume().unwrap(), b"hello phone");
+        assert!(!ring.has_new());
+        assert!(ring.consume().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wraps_around_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = ring(&dir, "wrap", 8);
+        for round in 0..5_u8 {
+            let data = [round; 5];
+            ring.write(&data).unwrap();
+            assert_eq!(ring.consume().unwrap(), data);
+        }
+        // split across the end of the data region and the start
+        ring.write(b"abcdef").unwrap();
+        assert_eq!(ring.peek().unwrap(), b"abcdef");
+        assert_eq!(ring.consume().unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn refuses_writes_that_dont_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = ring(&dir, "full", 8);
+        ring.write(b"12345").unwrap();
+        assert!(ring.write(b"6789").is_err());
+        // nothing of the refused write got in
+        ring.write(b"678").unwrap();
+        assert!(ring.write(b"9").is_err());
+        assert_eq!(ring.consume().unwrap(), b"12345678");
+        ring.write(b"87654321").unwrap();
+        assert_eq!(ring.consume().unwrap(), b"87654321");
+    }
+
+    #[test]
+    fn rejects_rings_without_room() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty");
+        assert!(Ring::open(path.to_str().unwrap(), "ring_test_empty", 0).is_err());
+        // a corrupt file claiming no capacity (which used to be a division
+        // by zero on the first write)
+        let mut contents = vec![0_u8; DATA_OFFSET + 8];
+        contents[MAGIC_OFFSET..MAGIC_OFFSET+8].copy_from_slice(&MAGIC.to_le_bytes());
+        std::fs::write(&path, contents).unwrap();
+        assert!(Ring::open(path.to_str().unwrap(), "ring_test_empty", 8).is_err());
+    }
+
+    // head and tail from a file we didn't write are checked rather than
+    // trusted (which used to underflow, and copy past the end of the map)
+    #[test]
+    fn rejects_corrupt_positions() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = ring(&dir, "corrupt", 8);
+        let path = dir.path().join("corrupt");
+        for (head, tail) in [(5_u64, 2_u64), (0, 9), (3, u64::MAX)] {
+            let mut contents = std::fs::read(&path).unwrap();
+            contents[HEAD_OFFSET..HEAD_OFFSET+8].copy_from_slice(&head.to_le_bytes());
+            contents[TAIL_OFFSET..TAIL_OFFSET+8].copy_from_slice(&tail.to_le_bytes());
+            std::fs::write(&path, contents).unwrap();
+            assert!(ring.write(b"x").is_err(), "{} {}", head, tail);
+            assert!(ring.peek().is_err(), "{} {}", head, tail);
+            assert!(ring.consume().is_err(), "{} {}", head, tail);
+        }
+    }
+
+    // threads writing to the same ring at once don't write over each other
+    #[test]
+    fn producers_take_turns() {
+        let dir = tempfile::tempdir().unwrap();
+        let ring = Arc::new(ring(&dir, "threads", 64 * 1024));
+        let writers: Vec<_> = (0..4_u8).map(|i| {
+            let ring = ring.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    ring.write(&[i; 4]).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let data = ring.consume().unwrap();
+        assert_eq!(data.len(), 4 * 1000 * 4);
+        for chunk in data.chunks(4) {
+            assert!(chunk.iter().all(|b| *b == chunk[0]), "{:?}", chunk);
+        }
+    }
+}
+
+//==================================<===|===>===================================
@@ -20,31 +20,117 @@
  */
 
 //==================================<===|===>=================================//
-use std::net::{TcpStream, TcpListener};
-use tungstenite;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use mio::{Interest, Registry, Token};
+use mio::net::{TcpStream, TcpListener};
 use tungstenite::{WebSocket, accept, ServerHandshake};
 use tungstenite::handshake::{MidHandshake, server::NoCallback, HandshakeError};
+use crate::http::{self, Request, StaticFiles};
 use crate::util::Result;
 
 
+//================================== Stream ==================================//
+// what a connection runs over: plain TCP, or TCP with TLS terminated here (see
+// Server::set_tls)
+enum Transport {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+}
+
+pub struct Stream {
+    transport: Transport,
+    // bytes read while finding out what kind of request this is, which are
+    // read again before anything else (so tungstenite sees the whole request)
+    unread: Vec<u8>,
+}
+
+impl Stream {
+    fn new(transport: Transport) -> Self {
+        Stream { transport, unread: Vec::new() }
+    }
+
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match &self.transport {
+            Transport::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.unread.is_empty() {
+            let n = buf.len().min(self.unread.len());
+            buf[..n].copy_from_slice(&self.unread[..n]);
+            self.unread.drain(..n);
+            return Ok(n);
+        }
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.transport {
+            Transport::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+
 //================================== Sawket ==================================//
+#[derive(Clone)]
 pub enum Msg {
     Text(String),
     Bytes(Vec<u8>),
 }
 
-pub type SawketId = String; // TODO: change to ID from browser
+pub type SawketId = String;
+
+// IPv4 peers of a dual-stack listener show up as IPv4-mapped IPv6 addresses
+// (::ffff:a.b.c.d) so turn those back into plain IPv4 addresses
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
 
 pub struct Sawket {
-    websocket: WebSocket<TcpStream>,
+    websocket: WebSocket<Stream>,
     addr: String,
     dead: bool,
+    // when anything (including a pong) last arrived
+    last_seen: Instant,
+    // the payload of the latest ping and when it went out
+    ping_seq: u64,
+    ping_sent: Option<Instant>,
+    // the round trip time of the latest ping that was answered
+    rtt: Option<Duration>,
 }
 
 impl Sawket {
-    pub fn new(websocket: WebSocket<TcpStream>) -> Result<Self> {
-        let addr = match websocket.get_ref().peer_addr() {
-            Ok(sock_addr) => sock_addr.to_string(),
+    pub fn new(websocket: WebSocket<Stream>) -> Result<Self> {
+        let addr = match websocket.get_ref().tcp_stream().peer_addr() {
+            Ok(sock_addr) => canonical_addr(sock_addr).to_string(),
             Err(e) => {
                 println!("failure getting websocket address: {}", e);
                 "0.0.0.0".to_string()
@@ -55,6 +141,10 @@ impl Sawket {
 	        websocket,
 	        addr,
 	        dead: false,
+            last_seen: Instant::now(),
+            ping_seq: 0,
+            ping_sent: None,
+            rtt: None,
 	    })
     }
 
@@ -66,6 +156,47 @@ impl Sawket {
 	    self.dead
     }
 
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    // Ping if it's been ping_interval since the last ping, and give up on the
+    // connection if nothing has come back in timeout (a phone that lost its
+    // network doesn't close anything, it just goes quiet)
+    pub fn heartbeat(&mut self, ping_interval: Duration, timeout: Duration) {
+        if self.dead {
+            return;
+        }
+        if self.last_seen.elapsed() >= timeout {
+            println!("Warning: Sawket {} is dying because it was silent for {:?}",
+                     self.addr, timeout);
+            self.dead = true;
+            return;
+        }
+        if self.ping_sent.is_some_and(|sent| sent.elapsed() < ping_interval) {
+            return;
+        }
+        self.ping_seq += 1;
+        self.ping_sent = Some(Instant::now());
+        let ping = tungstenite::Message::Ping(self.ping_seq.to_be_bytes().to_vec());
+        match self.websocket.write_message(ping) {
+	        Err(tungstenite::error::Error::Io(e))
+                if e.kind() == std::io::ErrorKind::WouldBlock => (),
+	        Err(e) => {
+	            println!("Warning: {} failed to ping: {}", &self.addr, e);
+	        }
+            Ok(()) => (),
+        }
+    }
+
+    fn handle_pong(&mut self, payload: &[u8]) {
+        if payload == self.ping_seq.to_be_bytes() {
+            if let Some(sent) = self.ping_sent {
+                self.rtt = Some(sent.elapsed());
+            }
+        }
+    }
+
     // Out: (a message if theres a valid one, whether there might still be messages left)
     pub fn recv_msg(&mut self) -> (Option<Msg>, bool) {
 	    if self.dead {
@@ -73,34 +204,41 @@ impl Sawket {
 	    }
 	    match self.websocket.read_message() {
 		    Ok(m) => {
+                self.last_seen = Instant::now();
 		        match m {
 			        tungstenite::Message::Close(_) => {
 			            self.dead = true;
-			            return (None, false)
+			            (None, false)
 			        }
 			        tungstenite::Message::Binary(v) => {
-			            return (Some(Msg::Bytes(v)), true);
+			            (Some(Msg::Bytes(v)), true)
 			        }
 			        tungstenite::Message::Text(s) => {
-			            return (Some(Msg::Text(s)), true);
+			            (Some(Msg::Text(s)), true)
 			        }
+                    // tungstenite answers pings itself
+                    tungstenite::Message::Ping(_) => (None, true),
+                    tungstenite::Message::Pong(payload) => {
+                        self.handle_pong(&payload);
+                        (None, true)
+                    }
 			        other => {
 			            println!("Warning: Sawket {} recved non-string non-binary \
 				                  message: {:?}",
 				                 self.addr, other);
-                        return (None, true)
+                        (None, true)
 			        }
 		        }
 		    }
 		    // no more messages available case
 		    Err(tungstenite::error::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                return (None, false);
+                (None, false)
 		    }
 		    Err(e) => {
 		        println!("Warning: Sawket {} is dying because: <{}>",
 			             self.addr, e);
 		        self.dead = true;
-                return (None, false);
+                (None, false)
 		    }
 	    }
     }
@@ -123,49 +261,197 @@ impl Sawket {
     }
 
     pub fn send_msg(&mut self, msg: Msg) {
-	    let res;
-	    match msg {
+	    let res = match msg {
 	        Msg::Text(t) => {
-		        res = self.websocket.write_message(tungstenite::Message::Text(t));
+		        self.websocket.write_message(tungstenite::Message::Text(t))
 	        }
 	        Msg::Bytes(b) => {
-		        res = self.websocket.write_message(tungstenite::Message::Binary(b));
+		        self.websocket.write_message(tungstenite::Message::Binary(b))
 	        }
+	    };
+	    match res {
+            // the message stays queued and goes out in flush()
+	        Err(tungstenite::error::Error::Io(e))
+                if e.kind() == std::io::ErrorKind::WouldBlock => (),
+	        Err(e) => {
+	            println!("Warning: {} websocket.write_message returned an Err {}", &self.addr, e);
+	        }
+            Ok(()) => (),
 	    }
-	    if let Err(e) = res {
-	        println!("Warning: {} websocket.write_message returned an Err {}", &self.addr, e);
+    }
+
+    // send anything that was queued because the socket wasn't writable
+    pub fn flush(&mut self) {
+	    if self.dead {
+	        return;
 	    }
+        match self.websocket.write_pending() {
+	        Err(tungstenite::error::Error::Io(e))
+                if e.kind() == std::io::ErrorKind::WouldBlock => (),
+	        Err(e) => {
+	            println!("Warning: {} websocket.write_pending returned an Err {}", &self.addr, e);
+	        }
+            Ok(()) => (),
+        }
     }
 }
 
 
 //================================== Server ==================================//
+// errors binding [::] that mean this machine has no IPv6 (rather than that the
+// port is taken, say)
+fn ipv6_unavailable(e: &std::io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    const EAFNOSUPPORT: i32 = 97;
+    #[cfg(target_os = "macos")]
+    const EAFNOSUPPORT: i32 = 47;
+    #[cfg(target_os = "windows")]
+    const EAFNOSUPPORT: i32 = 10047; // WSAEAFNOSUPPORT
+    matches!(e.kind(), std::io::ErrorKind::AddrNotAvailable | std::io::ErrorKind::Unsupported) ||
+        e.raw_os_error() == Some(EAFNOSUPPORT)
+}
+
+// a phone that hasn't finished its websocket handshake in this long is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// and one that hasn't taken all of an HTTP response in this long
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct PendingHandshake {
+    mid_handshake: MidHandshake<ServerHandshake<Stream, NoCallback>>,
+    started: Instant,
+}
+
+// a new connection whose request we're still reading to find out whether it
+// wants a websocket or a file
+struct PendingRequest {
+    stream: Stream,
+    request: Vec<u8>,
+    started: Instant,
+}
+
+// an HTTP response we're still writing
+struct PendingResponse {
+    stream: Stream,
+    response: Vec<u8>,
+    written: usize,
+    started: Instant,
+}
+
 pub struct Server {
-    server: TcpListener,
-    handshake_continuation: Option<MidHandshake<ServerHandshake<TcpStream, NoCallback>>>,
+    // one dual-stack listener, plus a separate IPv4 listener on systems where
+    // IPv6 sockets don't accept IPv4 connections
+    listeners: Vec<TcpListener>,
+    // handshakes that would have blocked; each one is continued independently
+    // so a slow phone doesn't hold up everybody else
+    pending_handshakes: Vec<PendingHandshake>,
+    pending_requests: Vec<PendingRequest>,
+    pending_responses: Vec<PendingResponse>,
+    // where plain HTTP requests are answered from (see http.rs)
+    static_files: Vec<StaticFiles>,
+    // HANDSHAKE_TIMEOUT, but tests don't want to wait that long
+    handshake_timeout: Duration,
+    // listeners and streams are registered here so that the main loop wakes
+    // up when any of them become ready
+    registry: Registry,
+    next_token: usize,
+    // accepted connections start a TLS handshake if this is set
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
 }
 
-type HandshakeResult = std::result::Result<WebSocket<TcpStream>,
-                                           HandshakeError<ServerHandshake<TcpStream, NoCallback>>>;
+type HandshakeResult = std::result::Result<WebSocket<Stream>,
+                                           HandshakeError<ServerHandshake<Stream, NoCallback>>>;
 
 impl Server {
-    pub fn new(port: &str) -> Result<Self> {
-	    let server = TcpListener::bind("0.0.0.0:".to_string()+port).unwrap();
-	    server.set_nonblocking(true)?;
+    // first_token: tokens from this one up are used for the server's sockets
+    pub fn new(port: &str, registry: &Registry, first_token: usize) -> Result<Self> {
+        let mut listeners: Vec<std::net::TcpListener> = vec![];
+        // bind the IPv4 listener to whatever port the IPv6 listener ended up
+        // on so that port "0" works
+        let mut port = port.to_string();
+        match std::net::TcpListener::bind("[::]:".to_string() + &port) {
+            Ok(listener) => {
+                port = listener.local_addr()?.port().to_string();
+                listeners.push(listener);
+            }
+            Err(e) if ipv6_unavailable(&e) => {
+                println!("Note: IPv6 unavailable, listening on IPv4 only: {}", e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        match std::net::TcpListener::bind("0.0.0.0:".to_string() + &port) {
+            Ok(listener) => listeners.push(listener),
+            // the dual-stack listener already covers IPv4
+            Err(e) if !listeners.is_empty() &&
+                e.kind() == std::io::ErrorKind::AddrInUse => (),
+            Err(e) => return Err(e.into()),
+        }
+        let registry = registry.try_clone()?;
+        let mut next_token = first_token;
+        let mut mio_listeners: Vec<TcpListener> = vec![];
+        for listener in listeners {
+	        listener.set_nonblocking(true)?;
+            let mut listener = TcpListener::from_std(listener);
+            registry.register(&mut listener, Token(next_token), Interest::READABLE)?;
+            next_token += 1;
+            mio_listeners.push(listener);
+        }
 	    Ok(Server {
-	        server,
-            handshake_continuation: None,
+	        listeners: mio_listeners,
+            pending_handshakes: Vec::new(),
+            pending_requests: Vec::new(),
+            pending_responses: Vec::new(),
+            static_files: Vec::new(),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            registry,
+            next_token,
+            #[cfg(feature = "tls")]
+            tls: None,
 	    })
     }
 
-    fn websocket_from_handshake_result(&mut self, result: HandshakeResult) ->
-        Option<WebSocket<TcpStream>> {
+    // serve wss:// instead of ws:// from now on
+    #[cfg(feature = "tls")]
+    pub fn set_tls(&mut self, config: Arc<rustls::ServerConfig>) {
+        self.tls = Some(config);
+    }
+
+    fn wrap_stream(&self, stream: TcpStream) -> Result<Stream> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let connection = rustls::ServerConnection::new(config.clone())?;
+            let stream = rustls::StreamOwned::new(connection, stream);
+            return Ok(Stream::new(Transport::Tls(Box::new(stream))));
+        }
+        Ok(Stream::new(Transport::Plain(stream)))
+    }
+
+    // serve plain HTTP requests for paths under prefix (e.g. "/") from the
+    // files in dir, or stop serving them if None
+    pub fn serve_files_from(&mut self, prefix: &str, dir: Option<PathBuf>) {
+        self.static_files.retain(|files| files.prefix() != prefix);
+        if let Some(dir) = dir {
+            self.static_files.push(StaticFiles::new(prefix, dir));
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn port(&self) -> u16 {
+        self.listeners[0].local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    fn websocket_from_handshake_result(&mut self, result: HandshakeResult,
+                                       started: Instant) ->
+        Option<WebSocket<Stream>> {
         match result {
 	        Ok(websocket) => {
                 Some(websocket)
 	        }
 	        Err(HandshakeError::Interrupted(mid_handshake)) => {
-                self.handshake_continuation = Some(mid_handshake);
+                self.pending_handshakes.push(PendingHandshake {
+                    mid_handshake,
+                    started,
+                });
                 None
 	        }
 	        Err(e) => {
@@ -175,48 +461,443 @@ impl Server {
         }
     }
 
-    fn get_next_websocket(&mut self) -> Option<WebSocket<TcpStream>> {
-        // in-progress handshaking connection
-        if let Some(mid_handshake) = self.handshake_continuation.take() {
-            let result = mid_handshake.handshake();
-            return self.websocket_from_handshake_result(result);
-        } 
-        // brand new connection
-	    match self.server.accept() {
-	        Ok((stream, _)) => {
-                if let Err(e) = stream.set_nonblocking(true) {
-                    println!("Failed to set stream to nonblocking before accept(): {}", e);
-                }
-                let result = accept(stream);
-                self.websocket_from_handshake_result(result)
-	        }
-	        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                None
-	        }
-            Err(e) => {
-		        println!("Warning: Unexpected error when trying to accept a connection: {}", e);
-                None
-            }                
-	    }
+    // give every in-progress handshake a chance to finish
+    fn continue_handshakes(&mut self) -> Vec<WebSocket<Stream>> {
+        let mut websockets: Vec<WebSocket<Stream>> = vec![];
+        for pending in std::mem::take(&mut self.pending_handshakes) {
+            if pending.started.elapsed() >= self.handshake_timeout {
+                println!("Warning: Dropping connection that didn't finish its \
+                          websocket handshake in time");
+                continue;
+            }
+            let result = pending.mid_handshake.handshake();
+            if let Some(websocket) =
+                self.websocket_from_handshake_result(result, pending.started) {
+                websockets.push(websocket);
+            }
+        }
+        websockets
     }
 
-    pub fn new_connections(&mut self) -> Vec<Sawket> {
-	    let mut sawkets: Vec<Sawket> = vec![];
-	    loop {
-            if let Some(websocket) = self.get_next_websocket() {
-                match Sawket::new(websocket) {
-		            Ok(sawket) => {
- 		                sawkets.push(sawket);
-		            }
-		            Err(e) => {
-		                println!("Warning: Error when trying to create a sawket: {}", e);
-		            }
+    // accept everything waiting on the listeners
+    fn accept_connections(&mut self) {
+        for i in 0..self.listeners.len() {
+            loop {
+	            match self.listeners[i].accept() {
+	                Ok((mut stream, _)) => {
+                        let token = Token(self.next_token);
+                        self.next_token += 1;
+                        if let Err(e) = self.registry.register(
+                            &mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                            println!("Warning: Failed to register stream: {}", e);
+                        }
+                        let stream = match self.wrap_stream(stream) {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                println!("Warning: Failed to set up TLS: {}", e);
+                                continue;
+                            }
+                        };
+                        self.pending_requests.push(PendingRequest {
+                            stream,
+                            request: Vec::new(),
+                            started: Instant::now(),
+                        });
+	                }
+	                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        break;
+	                }
+                    Err(e) => {
+		                println!("Warning: Unexpected error when trying to accept a connection: {}", e);
+                        break;
+                    }                
 	            }
+            }
+        }
+    }
+
+    // read what's arrived of each new connection's request and pass it on to
+    // tungstenite if it's a websocket upgrade or answer it if it isn't
+    fn read_requests(&mut self) -> Vec<WebSocket<Stream>> {
+        let mut websockets: Vec<WebSocket<Stream>> = vec![];
+        let mut buf = [0_u8; 4096];
+        'requests: for mut pending in std::mem::take(&mut self.pending_requests) {
+            let request = loop {
+                if let Some(request) = Request::parse(&pending.request) {
+                    break request;
+                }
+                if pending.request.len() > http::MAX_REQUEST_BYTES {
+                    println!("Warning: Dropping connection with an oversized request");
+                    continue 'requests;
+                }
+                match pending.stream.read(&mut buf) {
+                    Ok(0) => continue 'requests,
+                    Ok(n) => pending.request.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        if pending.started.elapsed() >= self.handshake_timeout {
+                            println!("Warning: Dropping connection that didn't \
+                                      send a request in time");
+                        } else {
+                            self.pending_requests.push(pending);
+                        }
+                        continue 'requests;
+                    }
+                    Err(e) => {
+                        println!("Warning: Error reading request: {}", e);
+                        continue 'requests;
+                    }
+                }
+            };
+            if request.is_websocket_upgrade() {
+                pending.stream.unread = pending.request;
+                let result = accept(pending.stream);
+                if let Some(websocket) =
+                    self.websocket_from_handshake_result(result, pending.started) {
+                    websockets.push(websocket);
+                }
             } else {
-                break;
+                let files = self.static_files.iter_mut()
+                    .filter(|files| files.serves(&request.target))
+                    .max_by_key(|files| files.prefix().len());
+                let response = match files {
+                    Some(files) => files.respond(&request),
+                    None => http::not_found(&request),
+                };
+                self.pending_responses.push(PendingResponse {
+                    stream: pending.stream,
+                    response,
+                    written: 0,
+                    started: Instant::now(),
+                });
             }
+        }
+        websockets
+    }
+
+    // write as much of each HTTP response as the sockets will take, closing
+    // the connections that are done
+    fn write_responses(&mut self) {
+        'responses: for mut pending in std::mem::take(&mut self.pending_responses) {
+            while pending.written < pending.response.len() {
+                match pending.stream.write(&pending.response[pending.written..]) {
+                    Ok(0) => continue 'responses,
+                    Ok(n) => pending.written += n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("Warning: Error writing response: {}", e);
+                        continue 'responses;
+                    }
+                }
+            }
+            let flushed = pending.written == pending.response.len() &&
+                pending.stream.flush().is_ok();
+            if flushed {
+                let _ = pending.stream.tcp_stream().shutdown(std::net::Shutdown::Write);
+            } else if pending.started.elapsed() >= RESPONSE_TIMEOUT {
+                println!("Warning: Dropping connection that didn't take its \
+                          response in time");
+            } else {
+                self.pending_responses.push(pending);
+            }
+        }
+    }
+
+    pub fn new_connections(&mut self) -> Vec<Sawket> {
+	    let mut sawkets: Vec<Sawket> = vec![];
+        let mut websockets = self.continue_handshakes();
+        self.accept_connections();
+        websockets.append(&mut self.read_requests());
+        self.write_responses();
+	    for websocket in websockets {
+            match Sawket::new(websocket) {
+		        Ok(sawket) => {
+ 		            sawkets.push(sawket);
+		        }
+		        Err(e) => {
+		            println!("Warning: Error when trying to create a sawket: {}", e);
+		        }
+	        }
 	    }
-	    return sawkets;
+	    sawkets
+    }
+}
+
+//=================================== Tests ==================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr, IpAddr};
+
+    #[test]
+    fn canonical_addr_unmaps_ipv4() {
+        let mapped = SocketAddr::new(
+            IpAddr::V6(Ipv4Addr::new(192, 168, 1, 23).to_ipv6_mapped()), 5000);
+        assert_eq!(canonical_addr(mapped).to_string(), "192.168.1.23:5000");
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)), 5000);
+        assert_eq!(canonical_addr(v4), v4);
+        let v6 = SocketAddr::new(
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1c2d, 0x3e4f, 0x5a6b, 1)), 5000);
+        assert_eq!(canonical_addr(v6).to_string(), "[fe80::1c2d:3e4f:5a6b:1]:5000");
+    }
+
+    // connect a websocket client to host and return the Sawket the server
+    // made for it
+    fn connect_from(host: &str) -> Sawket {
+        let poll = mio::Poll::new().unwrap();
+        let mut server = Server::new("0", poll.registry(), 0).unwrap();
+        let addr = format!("{}:{}", host, server.port());
+        let client = std::thread::spawn(move || {
+            let stream = std::net::TcpStream::connect(&addr).unwrap();
+            let url = format!("ws://{}", addr);
+            let (mut socket, _) = tungstenite::client(url, stream).unwrap();
+            // hold the connection open until the server has seen it
+            let _ = socket.read_message();
+        });
+        let start = Instant::now();
+        let sawket = loop {
+            if let Some(sawket) = server.new_connections().pop() {
+                break sawket;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no connection");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        let _ = sawket.websocket.get_ref().tcp_stream().shutdown(std::net::Shutdown::Both);
+        client.join().unwrap();
+        sawket
+    }
+
+    #[test]
+    fn accepts_ipv4_clients() {
+        let sawket = connect_from("127.0.0.1");
+        assert!(sawket.addr().starts_with("127.0.0.1:"), "{}", sawket.addr());
+    }
+
+    #[test]
+    fn accepts_ipv6_clients() {
+        let sawket = connect_from("[::1]");
+        assert!(sawket.addr().starts_with("[::1]:"), "{}", sawket.addr());
+    }
+
+    // only a missing IPv6 stack is worked around, not e.g. a port in use
+    #[test]
+    fn only_falls_back_to_ipv4_without_ipv6() {
+        use std::io::{Error, ErrorKind};
+        assert!(ipv6_unavailable(&Error::from(ErrorKind::AddrNotAvailable)));
+        assert!(ipv6_unavailable(&Error::from(ErrorKind::Unsupported)));
+        #[cfg(target_os = "linux")]
+        assert!(ipv6_unavailable(&Error::from_raw_os_error(97)));
+        assert!(!ipv6_unavailable(&Error::from(ErrorKind::AddrInUse)));
+        assert!(!ipv6_unavailable(&Error::from(ErrorKind::PermissionDenied)));
+        let poll = mio::Poll::new().unwrap();
+        let server = Server::new("0", poll.registry(), 0).unwrap();
+        assert!(Server::new(&server.port().to_string(), poll.registry(), 0).is_err());
+    }
+
+    // a phone that stalls halfway through its handshake doesn't hold up the
+    // ones behind it, and is dropped once it's had its time
+    #[test]
+    fn slow_handshakes_dont_block_others() {
+        let poll = mio::Poll::new().unwrap();
+        let mut server = Server::new("0", poll.registry(), 0).unwrap();
+        server.handshake_timeout = Duration::from_millis(300);
+        let addr = format!("127.0.0.1:{}", server.port());
+        let mut stalled = std::net::TcpStream::connect(&addr).unwrap();
+        stalled.write_all(b"GET / HTTP/1.1\r\nHost: pad\r\nUpgrade: websock").unwrap();
+        let stalled_at = Instant::now();
+        let clients: Vec<_> = (0..3).map(|_| {
+            let addr = addr.clone();
+            std::thread::spawn(move || {
+                let stream = std::net::TcpStream::connect(&addr).unwrap();
+                let (mut socket, _) = tungstenite::client(format!("ws://{}", addr), stream)
+                    .unwrap();
+                let _ = socket.read_message();
+            })
+        }).collect();
+        let mut sawkets = vec![];
+        while sawkets.len() < clients.len() {
+            assert!(stalled_at.elapsed() < Duration::from_millis(300),
+                    "handshakes waited for the stalled one");
+            sawkets.append(&mut server.new_connections());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(server.pending_requests.len(), 1);
+        while !server.pending_requests.is_empty() {
+            assert!(stalled_at.elapsed() < Duration::from_secs(5), "never dropped");
+            server.new_connections();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(stalled_at.elapsed() >= Duration::from_millis(300));
+        // and its connection is closed
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stalled.read(&mut [0; 16]).unwrap(), 0);
+        for sawket in &sawkets {
+            let _ = sawket.websocket.get_ref().tcp_stream().shutdown(std::net::Shutdown::Both);
+        }
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    // the same for a phone that has asked for a websocket but won't take the
+    // server's answer, leaving tungstenite's handshake half done
+    #[test]
+    fn stalled_websocket_handshakes_are_dropped() {
+        // keep a connection the phone never reads from full so that there's
+        // no room for the answer
+        fn fill(mut stream: &TcpStream) {
+            while stream.write(&[0; 65536]).is_ok() {}
+        }
+        let poll = mio::Poll::new().unwrap();
+        let mut server = Server::new("0", poll.registry(), 0).unwrap();
+        server.handshake_timeout = Duration::from_millis(300);
+        let addr = format!("127.0.0.1:{}", server.port());
+        let stalled_at = Instant::now();
+        let mut stalled = std::net::TcpStream::connect(&addr).unwrap();
+        stalled.write_all(b"GET / HTTP/1.1\r\nHost: pad\r\nUpgrade: websocket\r\n\
+                            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+        while server.pending_requests.is_empty() {
+            assert!(stalled_at.elapsed() < Duration::from_secs(5), "never accepted");
+            server.accept_connections();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        while server.pending_handshakes.is_empty() {
+            assert_eq!(server.pending_requests.len(), 1, "the upgrade went through");
+            fill(server.pending_requests[0].stream.tcp_stream());
+            server.new_connections();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let client = std::thread::spawn(move || {
+            let stream = std::net::TcpStream::connect(&addr).unwrap();
+            let (mut socket, _) = tungstenite::client(format!("ws://{}", addr), stream).unwrap();
+            let _ = socket.read_message();
+        });
+        // Out: whether it's still waiting
+        let keep_stalled = |server: &Server| match server.pending_handshakes.first() {
+            Some(pending) => {
+                fill(pending.mid_handshake.get_ref().get_ref().tcp_stream());
+                true
+            }
+            None => false,
+        };
+        let sawket = loop {
+            assert!(keep_stalled(&server), "handshakes waited for the stalled one");
+            if let Some(sawket) = server.new_connections().pop() {
+                break sawket;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        while keep_stalled(&server) {
+            assert!(stalled_at.elapsed() < Duration::from_secs(5), "never dropped");
+            server.new_connections();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(stalled_at.elapsed() >= Duration::from_millis(300));
+        // and its connection is closed once the filler has been read
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        std::io::copy(&mut stalled, &mut std::io::sink()).unwrap();
+        let _ = sawket.websocket.get_ref().tcp_stream().shutdown(std::net::Shutdown::Both);
+        client.join().unwrap();
+    }
+
+    // a browser can load the controller page from the port it then opens
+    // its websocket on
+    #[test]
+    fn serves_files_and_websockets_on_one_port() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<canvas></canvas>").unwrap();
+        let poll = mio::Poll::new().unwrap();
+        let mut server = Server::new("0", poll.registry(), 0).unwrap();
+        server.serve_files_from("/", Some(dir.path().to_path_buf()));
+        let addr = format!("127.0.0.1:{}", server.port());
+        let client = std::thread::spawn(move || {
+            let mut stream = std::net::TcpStream::connect(&addr).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: pad\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let stream = std::net::TcpStream::connect(&addr).unwrap();
+            let (mut socket, _) = tungstenite::client(format!("ws://{}", addr), stream).unwrap();
+            socket.write_message(tungstenite::Message::Text("loaded".into())).unwrap();
+            let _ = socket.read_message();
+            response
+        });
+        let start = Instant::now();
+        let mut sawkets = vec![];
+        let mut msgs = vec![];
+        while msgs.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            sawkets.append(&mut server.new_connections());
+            for sawket in &mut sawkets {
+                msgs.append(&mut sawket.recv_msgs());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(matches!(&msgs[0], Msg::Text(t) if t == "loaded"));
+        let _ = sawkets[0].websocket.get_ref().tcp_stream().shutdown(std::net::Shutdown::Both);
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("Content-Type: text/html"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n<canvas></canvas>"), "{}", response);
+    }
+
+    // a phone talking wss:// to a server with a self-signed certificate it
+    // trusts gets a Sawket that works like any other
+    #[cfg(feature = "tls")]
+    #[test]
+    fn talks_to_tls_clients() {
+        use rustls::pki_types::PrivatePkcs8KeyDer;
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        let poll = mio::Poll::new().unwrap();
+        let mut server = Server::new("0", poll.registry(), 0).unwrap();
+        server.set_tls(crate::tls::server_config(vec![cert.clone()], key.into()).unwrap());
+        let port = server.port();
+        let client = std::thread::spawn(move || {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add(cert).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let config = rustls::ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions().unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let connection = rustls::ClientConnection::new(
+                Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+            let stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let stream = rustls::StreamOwned::new(connection, stream);
+            let url = format!("wss://localhost:{}", port);
+            let (mut socket, _) = tungstenite::client(url, stream).unwrap();
+            socket.write_message(tungstenite::Message::Text("over tls".into())).unwrap();
+            assert_eq!(socket.read_message().unwrap(),
+                       tungstenite::Message::Binary(vec![1, 2, 3]));
+        });
+        let start = Instant::now();
+        let until = |done: &mut dyn FnMut() -> bool| {
+            while !done() {
+                assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let mut sawkets = vec![];
+        until(&mut || {
+            sawkets.append(&mut server.new_connections());
+            !sawkets.is_empty()
+        });
+        let mut sawket = sawkets.pop().unwrap();
+        assert!(sawket.addr().starts_with("127.0.0.1:"), "{}", sawket.addr());
+        let mut msgs = vec![];
+        until(&mut || {
+            msgs.append(&mut sawket.recv_msgs());
+            !msgs.is_empty()
+        });
+        assert!(matches!(&msgs[0], Msg::Text(t) if t == "over tls"));
+        sawket.send_msg(Msg::Bytes(vec![1, 2, 3]));
+        until(&mut || {
+            sawket.flush();
+            client.is_finished()
+        });
+        client.join().unwrap();
     }
 }
 //==================================<===|===>=================================//
@@ -18,19 +18,31 @@
  */
 
 //==================================<===|===>=================================//
-mod ipc;
+#![allow(clippy::upper_case_acronyms)]
 mod saws;
-mod systemlock;
+mod http;
 mod util;
 mod animal_names;
+mod identity;
+#[cfg(feature = "tls")]
+mod tls;
 //
 use saws::Msg;
+use identity::TokenIssuer;
+use controlpads::{clock, config, frame, ipc, multicast, stats, systemlock};
+use controlpads::{ClientHandle, ClientState, Event, Message, Timing};
+use controlpads::{controller, event};
+use controlpads::ipc::IpcBackend;
 use animal_names::{NUM_ANIMAL_NAMES, ANIMAL_NAMES};
 //
-use std::{str, collections::HashMap};
+use std::{str, collections::{HashMap, VecDeque}};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use mio::{Events, Interest, Poll, Registry, Token};
 use unidecode::unidecode;
 //
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
 
 #[cfg(debug_assertions)]
@@ -49,6 +61,27 @@ macro_rules! dbgprint {
 
 const MAX_NAME_CHARS: usize = 16;
 
+// how long a client whose websockets all died is kept around (suspended)
+// waiting for it to reconnect before the game is told it's gone. Override
+// with CONTROLPAD_GRACE_MS (0 drops clients as soon as they disconnect).
+const DEFAULT_GRACE_PERIOD_MS: u64 = 30_000;
+// most messages buffered for a suspended client before old ones are dropped
+const MAX_BACKLOG_MSGS: usize = 1024;
+
+// how often websockets are pinged (to measure latency and keep them busy)
+const PING_INTERVAL: Duration = Duration::from_secs(2);
+// a websocket that hasn't sent anything, not even a pong, in this long is
+// treated as disconnected. Override with CONTROLPAD_TIMEOUT_MS.
+const DEFAULT_SILENCE_TIMEOUT_MS: u64 = 10_000;
+
+// the main loop sleeps until a socket is ready or a game wakes it up, but
+// still makes a pass this often to expire timeouts and to pick up IPC writes
+// from games that don't wake us (e.g. built against an older library)
+const IDLE_TIMEOUT: Duration = Duration::from_millis(50);
+//
+const WAKE_TOKEN: Token = Token(0);
+const FIRST_SERVER_TOKEN: usize = 1;
+
 // RPC without arguments should always be 2 bytes
 const RPC_QUIT: &[u8] = &[0x99, 0x99];
 // const RPC_GETQR: &[u8] = &[0x98, 0x98];
@@ -59,6 +92,11 @@ const RPC_QUIT: &[u8] = &[0x99, 0x99];
 // byte header
 //const RPC_EXAMPLE_HEADER: &[u8] = &[0xA0, 0xA0, 0xA0];
 
+// binary messages starting with this byte aren't RPC. The rest of the message
+// is a payload for the game (from a client) or for a client (from the game).
+// No RPC may start with it.
+const GAME_BYTES_HEADER: u8 = 0xB0;
+
 
 //================================== Helpers =================================//
 // assign an animal for a new client
@@ -92,66 +130,131 @@ fn clean_name(name: &str) -> String {
     trimmed
 }
 
-//================================ IPC Helpers ===============================//
-// get the last two bytes of ip address from socket for identification
-fn sawket_id_base(sawk: &saws::Sawket) -> String {
-    let addr = sawk.addr();
-    let ip = addr.split(":").next().unwrap(); // unwrap because guarunteed
-    let id_bytes = ip.split(".").collect::<Vec<&str>>();
-    return id_bytes[2..4].join("x");
+// A pending sawket opens with a binary message [subid, token...] where token
+// is the ascii session token it was given in a previous '_token:<token>'
+// message (left off if the browser doesn't have one yet). We answer with the
+// token the browser should hold on to from now on.
+// Out: the CPID for sawket if it has finished negotiating
+fn negotiate_token(sawket: &mut saws::Sawket, issuer: &TokenIssuer) -> Option<CPID> {
+    let v = match sawket.recv_msg() {
+        (Some(Msg::Bytes(v)), _) => v,
+        (Some(Msg::Text(t)), _) => {
+            println!("Warning: received Msg::Text from pending sawket \
+                      : {:?}", t);
+            return None;
+        }
+        (None, _) => return None,
+    };
+    dbgprint!(" |< {} + {:?}", &sawket.addr(), &v);
+    if v.is_empty() {
+        println!("Warning: invalid negotiation message: {:?}", v);
+        return None;
+    }
+    let subid = v[0];
+    let presented = str::from_utf8(&v[1..]).unwrap_or("");
+    let (token, identity) = match issuer.verify(presented) {
+        Some(identity) => (presented.to_string(), identity),
+        None => {
+            if !presented.is_empty() {
+                println!("Note: {} presented an unrecognized token",
+                         sawket.addr());
+            }
+            issuer.issue()
+        }
+    };
+    sawket.send_msg(Msg::Text(format!("_token:{}", token)));
+    Some(identity + "-" + &subid.to_string())
 }
 
-// add to the list of connected clients
-fn write_cp_client(client: &CPClient) -> Result<()> {
-    let delin_id = client.id.clone() + str::from_utf8(&[0])?; // utf8 null byte
-    ipc::write("cp_clients", &delin_id)?;
+//================================ IPC Helpers ===============================//
+// update the list of connected clients
+fn rewrite_cp_clients(ipc: &dyn IpcBackend, clients: &[CPClient], info: &CPInfo) -> Result<()> {
+    let records: Vec<Message> = clients.iter()
+        .map(|c| Message::Bytes(c.handle(&info.get_name(&c.id)).to_record()))
+        .collect();
+    // encode first so the object is empty for as short a time as possible
+    let data = frame::encode(&records);
+    ipc.consume("cp_clients")?;
+    ipc.write("cp_clients", &data)?;
     Ok(())
 }
 
-// update the list of connected clients
-fn rewrite_cp_clients(clients: &Vec<CPClient>) -> Result<()> {
-    ipc::consume("cp_clients")?;
-    for c in clients {
-        write_cp_client(c)?;
-    }
+// update the round trip times the game can look up
+fn rewrite_stats(ipc: &dyn IpcBackend, rtts: &[(CPID, Duration)]) -> Result<()> {
+    let rtts: Vec<(&str, Duration)> = rtts.iter().map(|(id, rtt)| (id.as_str(), *rtt)).collect();
+    let data = stats::encode(&rtts);
+    ipc.consume(stats::STATS_OBJECT)?;
+    ipc.write(stats::STATS_OBJECT, &data)?;
     Ok(())
 }
 
 // read outbound messages from the game destined for client with id
-fn read_msgs_for_client(id: &CPID) -> Result<Vec<String>> {
-    let mut ret: Vec<String> = Vec::new();
+fn read_msgs_for_client(ipc: &dyn IpcBackend, id: &CPID) -> Result<Vec<Message>> {
     let ipc_name = id.clone() + "_out";
-    let msgs_string = ipc::consume(&ipc_name)?;
-    if msgs_string.len() == 0 {
-        return Ok(vec![]);
-    }
-    let mut parts = msgs_string.split(str::from_utf8(&[0])?)
-        .collect::<Vec<&str>>();
-    parts.pop(); // there will be nothing after last null byte
-    for p in &parts {
-        ret.push(String::from(*p));
-    }
-    Ok(ret)
+    frame::decode(&ipc.consume(&ipc_name)?)
 }
 
 // write inbound messages from the client with id for the game to receive
-fn write_msgs_from_client(id: &CPID, msgs: Vec<&str>) -> Result<()> {
-    let mut s = String::new();
-    for m in msgs {
-        s += m;
-        s += str::from_utf8(&[0])?;
-    }
+fn write_msgs_from_client(ipc: &dyn IpcBackend, id: &CPID,
+                          msgs: &[(Message, Timing)]) -> Result<()> {
     let ipc_name = id.clone() + "_in";
-    ipc::write(&ipc_name, &s)?;
+    ipc.write(&ipc_name, &frame::encode_timed(msgs))?;
     Ok(())
 }
 
+// read messages from the game for more than one client (None for all of them)
+fn read_multicasts(ipc: &dyn IpcBackend)
+                   -> Result<Vec<(Option<Vec<CPID>>, Message)>> {
+    let data = ipc.consume(multicast::MULTICAST_OBJECT)?;
+    multicast::decode(&data)
+}
+
+// pass a message from the game on to client (or to gamenite_msgs, for the
+// server to handle once we're done with the clients)
+fn forward_msg_from_target(client: &mut CPClient, m: Message,
+                           gamenite_msgs: &mut Vec<(CPID, String)>) {
+    match m {
+        Message::Text(t) if t.starts_with("_") => {
+            // GameNite protocol message
+            dbgprint!(">|  {}: '{}'", &client.id, t);
+            gamenite_msgs.push((client.id.clone(), t));
+        }
+        Message::Text(t) => {
+            // game protocol message
+            dbgprint!("--> {}: '{}'", &client.id, t);
+            client.send_msg(Msg::Text(t));
+        }
+        Message::Bytes(v) => {
+            // game protocol message
+            dbgprint!("--> {} + {:?}", &client.id, &v);
+            let mut bytes = Vec::with_capacity(v.len() + 1);
+            bytes.push(GAME_BYTES_HEADER);
+            bytes.extend_from_slice(&v);
+            client.send_msg(Msg::Bytes(bytes));
+        }
+    }
+}
+
+// the requests in rpc_out. SystemApps from before IPC objects were framed
+// write NUL-terminated text instead, which is still understood for now (see
+// CHANGELOG.md)
+fn decode_rpc(data: &[u8]) -> Result<Vec<String>> {
+    if data.first().is_none_or(|b| *b == frame::VERSION) {
+        return frame::decode_text(data);
+    }
+    println!("Warning: rpc_out holds unframed text; SystemApps should write \
+              frames (see controlpads::frame), this will stop working");
+    Ok(data.split(|b| *b == 0)
+        .filter(|request| !request.is_empty())
+        .map(|request| String::from_utf8_lossy(request).into_owned())
+        .collect())
+}
+
 // write GameNite protocol messages for SystemApps to handle
-fn write_rpc_message(data: &Vec<u8>) -> Result<()> {
+fn write_rpc_message(ipc: &dyn IpcBackend, data: &Vec<u8>) -> Result<()> {
     let ipc_name = "rpc_in";
     if *data == RPC_QUIT {
-        let s = "quit".to_string() + str::from_utf8(&[0])?;        
-        ipc::write(ipc_name, &s)?;        
+        ipc.write(ipc_name, &frame::encode(&[Message::Text("quit".to_string())]))?;
     } else {
         println!("Warning: invalid rpc message: {:?}", data);
     }
@@ -160,12 +263,11 @@ fn write_rpc_message(data: &Vec<u8>) -> Result<()> {
 }
 
 // handle GameNite protocol messages (passed as byte vector on sawkets)
-fn handle_bytes_from_client(data: &Vec<u8>) {
+fn handle_bytes_from_client(ipc: &dyn IpcBackend, data: &Vec<u8>) {
     if data.len() == 2 {
-        write_rpc_message(data)
+        write_rpc_message(ipc, data)
             .unwrap_or_else(|e| println!("Warning: Failed to write rpc message \
                                           with error: {}", e));
-        return;
     } else {
         println!("Warning: received byte vector less than 2 bytes long: \
                   {:?}", data);
@@ -177,33 +279,150 @@ fn handle_bytes_from_client(data: &Vec<u8>) {
 type CPID = String;
 struct CPClient {
     id: CPID,
+    // stable number the game can use instead of id (see ClientHandle::index)
+    index: u64,
+    joined: SystemTime,
     sawkets: Vec<saws::Sawket>,
+    // when the last sawket died if we're waiting for the client to reconnect
+    suspended_since: Option<Instant>,
+    // messages for the client that arrived while it was suspended
+    backlog: VecDeque<Msg>,
+    // how far the client's clock is from ours (see clock.rs)
+    clock: clock::ClockSync,
+    // when we last pinged the client's clock, None until it shows that it
+    // takes part in clock sync
+    clock_pinged: Option<Instant>,
+    // when the client says it sent its next game message, by its clock
+    stamp: Option<f64>,
 }
 
 impl CPClient {
-    fn new(sawket: saws::Sawket, subid: u8) -> Self {
-        let id_base = sawket_id_base(&sawket);
-        let id = id_base + "-" + &subid.to_string();
-        let mut sawkets = Vec::new();
-        sawkets.push(sawket);
+    fn new(sawket: saws::Sawket, id: CPID, index: u64) -> Self {
         CPClient {
             id,
-            sawkets,
+            index,
+            joined: SystemTime::now(),
+            sawkets: vec![sawket],
+            suspended_since: None,
+            backlog: VecDeque::new(),
+            clock: clock::ClockSync::new(),
+            clock_pinged: None,
+            stamp: None,
         }
     }
 
-    fn send_msg(&mut self, msg: String) {
+    fn send_msg(&mut self, msg: Msg) {
+        if self.is_suspended() {
+            if self.backlog.len() >= MAX_BACKLOG_MSGS {
+                println!("Warning: backlog for {} is full, dropping oldest \
+                          message", self.id);
+                self.backlog.pop_front();
+            }
+            self.backlog.push_back(msg);
+            return;
+        }
         for sawk in &mut self.sawkets {
-            sawk.send_msg(Msg::Text(msg.clone()));
+            sawk.send_msg(msg.clone());
         }
     }
 
+    // how the client is described to the game in cp_clients
+    fn handle(&self, name: &str) -> ClientHandle {
+        let state = if self.is_suspended() {
+            ClientState::Suspended
+        } else {
+            ClientState::Connected
+        };
+        ClientHandle::new(&self.id, self.index, name, self.joined, state)
+    }
+
+    fn is_suspended(&self) -> bool {
+        self.suspended_since.is_some()
+    }
+
+    fn suspend(&mut self) {
+        self.suspended_since = Some(Instant::now());
+    }
+
+    // send everything that was buffered while suspended in the order it came
+    fn resume(&mut self) {
+        self.suspended_since = None;
+        for msg in std::mem::take(&mut self.backlog) {
+            self.send_msg(msg);
+        }
+    }
+
+    fn grace_expired(&self, grace_period: Duration) -> bool {
+        self.suspended_since
+            .map(|since| since.elapsed() >= grace_period)
+            .unwrap_or(false)
+    }
+
     fn recv_msgs(&mut self) -> Vec<Msg> {
         let mut msgs = Vec::new();
         for sawk in &mut self.sawkets {
             msgs.append(&mut sawk.recv_msgs());
         }
-        return msgs;
+        msgs
+    }
+
+    // the best round trip time of the client's live websockets
+    fn rtt(&self) -> Option<Duration> {
+        self.sawkets.iter()
+            .filter(|s| !s.is_dead())
+            .filter_map(|s| s.rtt())
+            .min()
+    }
+
+    // '_ping:<t0>', '_pong:<t0>:<t1>:<t2>' and '_at:<t>' (see clock.rs) are
+    // handled here rather than with the other GameNite messages because
+    // they're about when they arrived. Returns false for any other message.
+    fn handle_clock_message(&mut self, msg: &str, received: SystemTime) -> bool {
+        let parts: Vec<&str> = msg.split(':').collect();
+        let times: Option<Vec<f64>> = parts[1..].iter().map(|t| clock::parse_millis(t)).collect();
+        match (parts[0], times.as_deref()) {
+            (clock::PING, Some([_])) => {
+                let pong = format!("{}:{}:{:.3}:{:.3}", clock::PONG, parts[1],
+                                   clock::to_millis(received),
+                                   clock::to_millis(SystemTime::now()));
+                self.send_msg(Msg::Text(pong));
+            }
+            (clock::PONG, Some(&[t0, t1, t2])) => {
+                self.clock.add_sample(t0, t1, t2, clock::to_millis(received));
+            }
+            (clock::AT, Some(&[t])) => self.stamp = Some(t),
+            (clock::PING | clock::PONG | clock::AT, _) => {
+                println!("Warning: invalid clock message {} from {}", msg, self.id);
+            }
+            _ => return false,
+        }
+        // ping back at the next heartbeat
+        self.clock_pinged.get_or_insert_with(|| {
+            Instant::now().checked_sub(PING_INTERVAL).unwrap_or_else(Instant::now)
+        });
+        true
+    }
+
+    // keep measuring the clock of a client that takes part in clock sync
+    fn clock_heartbeat(&mut self, interval: Duration) {
+        let Some(pinged) = self.clock_pinged else { return };
+        if self.is_suspended() || pinged.elapsed() < interval {
+            return;
+        }
+        let ping = format!("{}:{:.3}", clock::PING, clock::to_millis(SystemTime::now()));
+        self.send_msg(Msg::Text(ping));
+        self.clock_pinged = Some(Instant::now());
+    }
+
+    // the timing of a game message from the client that arrived at received:
+    // sent when the client stamped it if we know its clock, otherwise half a
+    // round trip earlier
+    fn timing(&mut self, received: SystemTime) -> Timing {
+        let stamped = self.stamp.take().and_then(|t| self.clock.to_local(t));
+        let sent = stamped
+            .or_else(|| self.rtt().and_then(|rtt| received.checked_sub(rtt / 2)))
+            .unwrap_or(received);
+        Timing { received_at: received, sent_at: sent.min(received) }
     }
 
     fn is_dead(&self) -> bool {
@@ -212,7 +431,7 @@ impl CPClient {
                 return false;
             }
         }
-        return true;
+        true
     }
 
     fn clear_dead_sawkets(&mut self) {
@@ -265,7 +484,7 @@ impl CPInfo {
 
     fn try_change_name(&mut self, id: &CPID, name: &str) {
         let cleaned_name = clean_name(name);
-        if cleaned_name.len() == 0 {
+        if cleaned_name.is_empty() {
             // we don't allow empty name
             return;
         }
@@ -310,7 +529,7 @@ impl CPInfo {
         };
     }
 
-    fn get_name(&mut self, id: &CPID) -> String {
+    fn get_name(&self, id: &CPID) -> String {
         self.name_from_id.get(id)
             .map(|s| s.to_string())
             .unwrap_or_else(|| {
@@ -329,20 +548,60 @@ impl CPInfo {
 //================================= CPServer =================================//
 struct CPServer {
     server: saws::Server,
-    // pending_sawkets: The Sawkets that have not yet sent a subid and
-    // therefore have not become valid CPClients yet
+    // pending_sawkets: The Sawkets that have not yet negotiated a session
+    // token and therefore have not become valid CPClients yet
     pending_sawkets: Vec<saws::Sawket>,
+    // signs and checks the session tokens that identify clients
+    issuer: TokenIssuer,
     clients: Vec<CPClient>,
+    // index for the next new client
+    next_client_index: u64,
     // contains data about clients like the associated name
     info: CPInfo,
+    // how long a disconnected client stays suspended before being dropped
+    grace_period: Duration,
+    // how long a websocket can go without sending anything before it's
+    // treated as disconnected
+    silence_timeout: Duration,
+    // the round trip times in the stats object
+    published_rtts: Vec<(CPID, Duration)>,
+    // whether the game wants events (see controlpads::event) instead of
+    // messages in <id>_in and _suspended/_resumed/_name notices
+    events_subscribed: bool,
+    // whether games that don't subscribe to events get _suspended/_resumed
+    // in <id>_in (off by default since older games would take them for
+    // input; they can see suspended clients in cp_clients either way)
+    suspend_notices: bool,
+    // whether we wrote something the game reads during this update
+    game_notified: bool,
+    // the URL of the game's controller (see controlpads::controller), None
+    // for the default page
+    controller: Option<String>,
+    // bumped for every directory bundle so each gets its own URL
+    controller_generation: u64,
+    // where the IPC objects shared with the game live
+    ipc: Arc<dyn IpcBackend>,
 }
 
 impl CPServer {
-    fn new(port: &str) -> Self {
+    fn new(port: &str, grace_period: Duration, silence_timeout: Duration,
+           registry: &Registry, ipc: Arc<dyn IpcBackend>) -> Self {
         CPServer {
-            server: saws::Server::new(port).unwrap(), // unwrap because fatal
+            grace_period,
+            silence_timeout,
+            published_rtts: vec![],
+            ipc,
+            // unwrap because fatal
+            server: saws::Server::new(port, registry, FIRST_SERVER_TOKEN).unwrap(),
             clients: vec![],
+            next_client_index: 0,
+            events_subscribed: false,
+            suspend_notices: false,
+            game_notified: false,
+            controller: None,
+            controller_generation: 0,
             pending_sawkets: vec![],
+            issuer: TokenIssuer::new(),
             info: CPInfo::new(),
         }
     }
@@ -350,114 +609,282 @@ impl CPServer {
     // If an existing CPClient exists with this ID then add this sawket to that
     // cpclient, otherwise the ID is unique so create a new cpclient to hold
     // the sawket
-    fn incorporate_new_sawket(&mut self, sawket: saws::Sawket, subid: u8) {
-        let id_base = sawket_id_base(&sawket);
-        let new_sawk_id = id_base + "-" + &subid.to_string();
+    fn incorporate_new_sawket(&mut self, mut sawket: saws::Sawket, new_sawk_id: CPID) {
+        if let Some(url) = &self.controller {
+            sawket.send_msg(Msg::Text(format!("{}{}", controller::CONTROLLER_MSG, url)));
+        }
         let maybe_client = self.clients
-            .iter_mut().find(|c| &c.id == &new_sawk_id);
+            .iter_mut().find(|c| c.id == new_sawk_id);
         if let Some(client) = maybe_client {
-                client.add_sawket(sawket);
+            client.add_sawket(sawket);
+            if client.is_suspended() {
+                client.resume();
+                dbgprint!("resumed: {}", &new_sawk_id);
+                if self.events_subscribed {
+                    self.publish_client_event(&new_sawk_id, Event::Reconnected);
+                } else if self.suspend_notices {
+                    self.send_message_to_target(&new_sawk_id, "_resumed".to_string());
+                }
+                self.publish_clients();
+            }
         } else {
-            let client = CPClient::new(sawket, subid);
+            let client = CPClient::new(sawket, new_sawk_id, self.next_client_index);
+            self.next_client_index += 1;
             self.info.add_client(&client.id);
-            write_cp_client(&client)
-                .unwrap_or_else(|e| 
-                    println!("Failure writing to cp_clients for new client: \
-                              {}", e)
-                );
+            let id = client.id.clone();
             self.clients.push(client);
+            self.publish_clients();
+            self.publish_client_event(&id, Event::Joined);
             dbgprint!("clients: {:?}", self.clients.iter()
                       .map(|x| &x.id).collect::<Vec<&CPID>>());
         }
     }
 
+    // how the client with id currently looks to the game
+    fn client_handle(&self, id: &CPID) -> Option<ClientHandle> {
+        self.clients.iter()
+            .find(|c| &c.id == id)
+            .map(|c| c.handle(&self.info.get_name(id)))
+    }
+
+    // tell a subscribed game about something that happened
+    fn publish_event(&mut self, event: Event) {
+        if !self.events_subscribed {
+            return;
+        }
+        self.ipc.write(event::EVENTS_OBJECT, &event.encode())
+            .unwrap_or_else(|e| println!("Failure writing event: {}", e));
+        self.game_notified = true;
+    }
+
+    // publish_event for an event that just needs the client's handle
+    fn publish_client_event(&mut self, id: &CPID, make_event: fn(ClientHandle) -> Event) {
+        if let Some(handle) = self.client_handle(id) {
+            self.publish_event(make_event(handle));
+        }
+    }
+
+    // rewrite cp_clients to match our clients
+    fn publish_clients(&mut self) {
+        rewrite_cp_clients(self.ipc.as_ref(), &self.clients, &self.info)
+            .unwrap_or_else(|e| 
+                println!("Failure rewriting cp_clients: {}", e)
+            );
+        self.game_notified = true;
+    }
+
+    // everything the server does each time it wakes up
+    pub fn update(&mut self) {
+        self.accept_new_sawkets();
+        self.handle_negotiations();
+        self.handle_messages_from_target();
+        self.handle_messages_from_clients();
+        self.check_heartbeats();
+        self.clear_dead_clients();
+        self.publish_stats();
+        self.handle_rpc_from_target();
+        self.flush_clients();
+        if std::mem::take(&mut self.game_notified) {
+            self.ipc.notify_game();
+        }
+    }
+
+    // ping clients (and phones that haven't said who they are yet) and give
+    // up on the ones that have gone quiet
+    pub fn check_heartbeats(&mut self) {
+        let ping_interval = PING_INTERVAL.min(self.silence_timeout / 3);
+        for sawk in &mut self.pending_sawkets {
+            sawk.heartbeat(ping_interval, self.silence_timeout);
+        }
+        for client in &mut self.clients {
+            for sawk in &mut client.sawkets {
+                sawk.heartbeat(ping_interval, self.silence_timeout);
+            }
+            client.clock_heartbeat(ping_interval);
+        }
+    }
+
+    // let the game know if any round trip times changed
+    pub fn publish_stats(&mut self) {
+        let rtts: Vec<(CPID, Duration)> = self.clients.iter()
+            .filter_map(|c| c.rtt().map(|rtt| (c.id.clone(), rtt)))
+            .collect();
+        if rtts == self.published_rtts {
+            return;
+        }
+        rewrite_stats(self.ipc.as_ref(), &rtts).unwrap_or_else(|e| {
+            println!("Failure rewriting {}: {}", stats::STATS_OBJECT, e);
+        });
+        self.published_rtts = rtts;
+    }
+
     pub fn accept_new_sawkets(&mut self) {
         self.pending_sawkets.append(&mut self.server.new_connections());
     }
 
-    // For websockets that have died, remove the CPClient from our list and
-    // update the cp_clients ipc object to reflect that
+    // For websockets that have died, suspend the CPClient (if there's a grace
+    // period) and once the client has been gone too long remove it from our
+    // list and update the cp_clients ipc object to reflect that
     pub fn clear_dead_clients(&mut self) {
         self.clients.iter_mut().for_each(|x| x.clear_dead_sawkets());
-        let old_len = self.clients.len();
-        self.clients.retain(|x| ! x.is_dead());
-        if self.clients.len() == old_len {
+        let mut newly_suspended = Vec::<CPID>::new();
+        if !self.grace_period.is_zero() {
+            for client in &mut self.clients {
+                if client.is_dead() && !client.is_suspended() {
+                    client.suspend();
+                    newly_suspended.push(client.id.clone());
+                }
+            }
+        }
+        let any_suspended = !newly_suspended.is_empty();
+        for id in newly_suspended {
+            dbgprint!("suspended: {}", &id);
+            if self.events_subscribed {
+                self.publish_client_event(&id, Event::Suspended);
+            } else if self.suspend_notices {
+                self.send_message_to_target(&id, "_suspended".to_string());
+            }
+        }
+        let grace_period = self.grace_period;
+        let (kept, gone): (Vec<CPClient>, Vec<CPClient>) = self.clients.drain(..)
+            .partition(|x| {
+                ! x.is_dead() || (x.is_suspended() && ! x.grace_expired(grace_period))
+            });
+        self.clients = kept;
+        if gone.is_empty() && !any_suspended {
             return;
         }
-        rewrite_cp_clients(&self.clients)
-                .unwrap_or_else(|e| 
-                    println!("Failure rewriting cp_clients: {}", e)
-                );
+        self.publish_clients();
+        for client in gone {
+            let handle = client.handle(&self.info.get_name(&client.id));
+            self.publish_event(Event::Left(handle));
+        }
         dbgprint!("clients: {:?}", self.clients.iter()
                   .map(|x| &x.id).collect::<Vec<&CPID>>());
     }
 
-    pub fn handle_subids(&mut self) {
-        let mut subids: Vec<u8> = Vec::new();
-        let mut got_subid = |sawket: &mut saws::Sawket| -> bool {
-            let mut should = false;
-            if let (Some(m), _) = sawket.recv_msg() {
-                if let Msg::Bytes(v) = m {
-                    dbgprint!(" |< {} + {:?}", &sawket.addr(), &v);
-                    if v.len() > 0 {
-                        subids.push(v[0]);
-                        should = true;
-                    } 
-                    if v.len() != 1 {
-                        println!("Warning: invalid subid: {:?}", v);
-                    }
-                } else if let Msg::Text(t) = m {
-                    println!("Warning: received Msg::Text from pending sawket \
-                              : {:?}", t);
-                } else {
-                    println!("Warning: should be unreachable 932845");
-                }
-            }
-            return should
-        };
+    pub fn handle_negotiations(&mut self) {
         let mut i = 0;
-        let mut unpended_sawkets: Vec<saws::Sawket> = Vec::new();        
+        let mut unpended: Vec<(saws::Sawket, CPID)> = Vec::new();
         while i < self.pending_sawkets.len() {
-            if got_subid(&mut self.pending_sawkets[i]) {
-                unpended_sawkets.push(self.pending_sawkets.remove(i));
+            let sawket = &mut self.pending_sawkets[i];
+            if let Some(id) = negotiate_token(sawket, &self.issuer) {
+                unpended.push((self.pending_sawkets.remove(i), id));
             } else {
                 i += 1;
             }
         }
-        while unpended_sawkets.len() > 0 {
-            self.incorporate_new_sawket(unpended_sawkets.remove(0),
-                                        subids.remove(0));
+        for (sawket, id) in unpended {
+            self.incorporate_new_sawket(sawket, id);
         }
+        // closed, broken or (see check_heartbeats) silent before negotiating
+        self.pending_sawkets.retain(|sawket| !sawket.is_dead());
     }
     
-    // Out: whether or not all clients should be reloaded
-    pub fn read_reload(&mut self) -> Result<bool> {
+    // Out: the messages the game (or the console) sent to the server
+    pub fn read_rpc_out(&mut self) -> Result<Vec<String>> {
         let ipc_name = "rpc_out";
         //read here
-        let rpc_contents = ipc::consume(&ipc_name)?;
-        if rpc_contents.len() == 0 {
-            return Ok(false);
-        }
-        let mut parts = rpc_contents.split(str::from_utf8(&[0])?)
-            .collect::<Vec<&str>>();
-        parts.pop();
-        for message in parts {
-            if message == "reload" {
-                return Ok(true)
+        let rpc_contents = self.ipc.consume(ipc_name)?;
+        decode_rpc(&rpc_contents)
+    }
+
+    // send whatever couldn't be written earlier because a socket was full
+    pub fn flush_clients(&mut self) {
+        for sawk in &mut self.pending_sawkets {
+            sawk.flush();
+        }
+        for client in &mut self.clients {
+            for sawk in &mut client.sawkets {
+                sawk.flush();
             }
         }
-        return Ok(false);
     }
 
-    // notify all clients that they should refresh their webpage
-    pub fn send_reloads_to_clients(&mut self)  {
-        let should_reload = self.read_reload().unwrap_or_else( |e| {
-            println!("Failed to read reload message with error {}", e);
-            false
+    // handle reload, controller and event subscription requests
+    pub fn handle_rpc_from_target(&mut self) {
+        let messages = self.read_rpc_out().unwrap_or_else( |e| {
+            println!("Failed to read rpc_out with error {}", e);
+            vec![]
         });
-        if !should_reload {
-            return;
+        let mut should_reload = false;
+        for message in messages {
+            if message == "reload" {
+                should_reload = true;
+                // a reload usually means a different game so it has to
+                // subscribe again if it wants events and register its own
+                // controller (the reload takes care of telling clients)
+                self.set_events_subscribed(false);
+                self.mount_controller("");
+            } else if let Some(bundle) = message.strip_prefix(controller::SET_CONTROLLER) {
+                if self.mount_controller(bundle) {
+                    self.send_controller_to_clients();
+                }
+            } else if message == event::SUBSCRIBE {
+                self.set_events_subscribed(true);
+            } else if message == event::UNSUBSCRIBE {
+                self.set_events_subscribed(false);
+            } else {
+                println!("Warning: unrecognized rpc_out message: {}", message);
+            }
+        }
+        if should_reload {
+            self.send_reloads_to_clients();
+        }
+    }
+
+    // serve bundle (see controlpads::controller) as the controller from now on
+    // Out: whether the controller changed
+    fn mount_controller(&mut self, bundle: &str) -> bool {
+        let mount = |generation: u64| format!("{}{}/", controller::MOUNT_PREFIX, generation);
+        let is_dir = !bundle.is_empty() && !controller::is_url(bundle);
+        if is_dir && !std::path::Path::new(bundle).is_dir() {
+            println!("Warning: controller bundle {} is not a directory", bundle);
+            return false;
+        }
+        // stop serving the last game's files
+        self.server.serve_files_from(&mount(self.controller_generation), None);
+        let url = if bundle.is_empty() {
+            None
+        } else if is_dir {
+            self.controller_generation += 1;
+            let prefix = mount(self.controller_generation);
+            self.server.serve_files_from(&prefix, Some(bundle.into()));
+            Some(prefix)
+        } else {
+            Some(bundle.to_string())
+        };
+        let changed = url != self.controller;
+        self.controller = url;
+        changed
+    }
+
+    fn send_controller_to_clients(&mut self) {
+        let msg = format!("{}{}", controller::CONTROLLER_MSG,
+                          self.controller.as_deref().unwrap_or(""));
+        for client in &mut self.clients {
+            client.send_msg(Msg::Text(msg.clone()));
         }
+    }
+
+    fn set_events_subscribed(&mut self, subscribed: bool) {
+        if subscribed {
+            self.events_subscribed = true;
+            // start the game off with everyone who's already here, even if
+            // we thought it was subscribed (a game that restarted, or a
+            // second process, subscribing again knows nobody yet)
+            let ids: Vec<CPID> = self.clients.iter().map(|c| c.id.clone()).collect();
+            for id in ids {
+                self.publish_client_event(&id, Event::Joined);
+            }
+        } else if self.events_subscribed {
+            self.publish_event(Event::Unsubscribed);
+            self.events_subscribed = false;
+        }
+    }
+
+    // notify all clients that they should refresh their webpage
+    fn send_reloads_to_clients(&mut self)  {
         // go through clients and send vec![0x1] which means reload
         for client in &mut self.clients {
             for sawk in & mut client.sawkets {
@@ -472,28 +899,47 @@ impl CPServer {
         let mut gamenite_msgs = Vec::<(CPID, String)>::new();
         for client in &mut self.clients {
             let msgs = client.recv_msgs();
-            let mut game_msgs = Vec::<String>::new();
+            let received = SystemTime::now();
+            let mut game_msgs = Vec::<(Message, Timing)>::new();
             for m in msgs {
                 match m {
                     Msg::Text(t) => {
-                        if t.starts_with("_") {
+                        if client.handle_clock_message(&t, received) {
+                            dbgprint!(" |< {}: '{}'", &client.id, &t);
+                        } else if t.starts_with("_") {
                             // GameNite protocol message
                             dbgprint!(" |< {}: '{}'", &client.id, &t);
                             gamenite_msgs.push((client.id.clone(), t));   
                         } else {
                             // game protocol message                            
                             dbgprint!("<-- {}: '{}'", &client.id, &t);
-                            game_msgs.push(t);    
+                            game_msgs.push((Message::Text(t), client.timing(received)));
                         }
                     }
+                    Msg::Bytes(v) if v.first() == Some(&GAME_BYTES_HEADER) => {
+                        // game protocol message
+                        dbgprint!("<-- {} + {:?}", &client.id, &v[1..]);
+                        let timing = client.timing(received);
+                        game_msgs.push((Message::Bytes(v[1..].to_vec()), timing));
+                    }
                     Msg::Bytes(v) => {
                         dbgprint!(" |< {} + {:?}", &client.id, &v);
-                        handle_bytes_from_client(&v);
+                        handle_bytes_from_client(self.ipc.as_ref(), &v);
                     }
                 }
             }
-            if game_msgs.len() != 0 {
-                write_msgs_from_client(&client.id, game_msgs.iter().map(|s| s.as_str()).collect())
+            if !game_msgs.is_empty() {
+                self.game_notified = true;
+            }
+            if self.events_subscribed {
+                let handle = client.handle(&self.info.get_name(&client.id));
+                for (msg, timing) in game_msgs {
+                    let event = Event::Message(handle.clone(), msg, timing);
+                    self.ipc.write(event::EVENTS_OBJECT, &event.encode())
+                        .unwrap_or_else(|e| println!("Failure writing event: {}", e));
+                }
+            } else if !game_msgs.is_empty() {
+                write_msgs_from_client(self.ipc.as_ref(), &client.id, &game_msgs)
                     .unwrap_or_else(|e| {
                         println!("Warning: Failure writing ipc from client to \
                                   target. Error: {}", e);
@@ -508,25 +954,33 @@ impl CPServer {
     // for each "_out" ipc object that has new messages, send those messages
     // over websocket to the associated client
     pub fn handle_messages_from_target(&mut self) {
-        // TODO: In the first pass of loop over self.clients, collect up the
-        //       messages. In the second pass, send to all clients with that ID
         let mut gamenite_msgs = Vec::<(CPID, String)>::new();
         for client in &mut self.clients {
-            let msgs = read_msgs_for_client(&client.id)
+            let msgs = read_msgs_for_client(self.ipc.as_ref(), &client.id)
                 .unwrap_or_else(|e| {
                     println!("Failure reading message for {}: {}",
                              &client.id, e);
                     vec![]
                 });
             for m in msgs {
-                if m.starts_with("_") {
-                    // GameNite protocol message
-                    dbgprint!(">|  {}: '{}'", &client.id, m);
-                    gamenite_msgs.push((client.id.clone(), m));    
-                } else {
-                    // game protocol message
-                    dbgprint!("--> {}: '{}'", &client.id, m);
-                    client.send_msg(m);    
+                forward_msg_from_target(client, m, &mut gamenite_msgs);
+            }
+        }
+        // messages for several clients are written once and fanned out here
+        let multicasts = read_multicasts(self.ipc.as_ref())
+            .unwrap_or_else(|e| {
+                println!("Failure reading multicast messages: {}", e);
+                vec![]
+            });
+        for (ids, m) in multicasts {
+            if let Some(ids) = &ids {
+                for id in ids.iter().filter(|id| !self.clients.iter().any(|c| &c.id == *id)) {
+                    println!("Warning: multicast to nonexistent client {}", id);
+                }
+            }
+            for client in &mut self.clients {
+                if ids.as_ref().is_none_or(|ids| ids.contains(&client.id)) {
+                    forward_msg_from_target(client, m.clone(), &mut gamenite_msgs);
                 }
             }
         }
@@ -550,12 +1004,21 @@ impl CPServer {
         };
         // send
         dbgprint!(" |> {}: '{}'", &client.id, &msg);
-        client.send_msg(msg);
+        client.send_msg(Msg::Text(msg));
     }
 
     fn send_message_to_target(&mut self, id: &CPID, msg: String) {
         dbgprint!("<|  {}: '{}'", id, &msg);
-        write_msgs_from_client(id, vec![&msg])
+        if self.events_subscribed {
+            if let Some(handle) = self.client_handle(id) {
+                let timing = Timing::at(SystemTime::now());
+                self.publish_event(Event::Message(handle, Message::Text(msg), timing));
+            }
+            return;
+        }
+        self.game_notified = true;
+        let timed = (Message::Text(msg.clone()), Timing::at(SystemTime::now()));
+        write_msgs_from_client(self.ipc.as_ref(), id, &[timed])
             .unwrap_or_else(|e| {
                 println!("Error: failed to send message to target ({};{}):{}",
                          id, msg, e);
@@ -566,13 +1029,13 @@ impl CPServer {
     fn handle_gamenite_message(&mut self, id: &CPID, message: String) {
         let parts: Vec<&str> = message.split(":").collect();
         if parts[0] == "_get_name" {
-            self.gamenite_get_name(&id, &parts[1..]);
+            self.gamenite_get_name(id, &parts[1..]);
         } else if parts[0] == "_change_name" { 
             self.gamenite_change_name(id, &parts[1..]);
         } else if parts[0] == "_print" {
             self.gamenite_print(id, &parts[1..]);
         }
-        // TODO ping
+        // clock sync messages never get here (see CPClient::handle_clock_message)
         else {
             println!("Warning: received unrecognized underscore message: {}\n\
                       Messages sent over this protocol which start with _ are \
@@ -583,7 +1046,7 @@ impl CPServer {
 
     // '_get_name'
     fn gamenite_get_name(&mut self, id: &CPID, args: &[&str]) {
-        if args.len() != 0 {
+        if !args.is_empty() {
             println!("Warning: invalid message _get_name:{}. _get_name \
                       takes no arguments", args.join(":"));
             return;
@@ -602,15 +1065,23 @@ impl CPServer {
                       '_change_name:<new-name>'", args.join(":"));
             return;
         }
+        let old_name = self.info.get_name(id);
         self.info.try_change_name(id, args[0]);
+        self.publish_clients();
         let name = self.info.get_name(id);
         self.send_message_to_client(id, format!("_name:{}", name));
-        self.send_message_to_target(id, format!("_name:{}", name));
+        if !self.events_subscribed {
+            self.send_message_to_target(id, format!("_name:{}", name));
+        } else if name != old_name {
+            if let Some(client) = self.client_handle(id) {
+                self.publish_event(Event::Renamed { client, old_name });
+            }
+        }
     }
 
     // '_print'
     fn gamenite_print(&mut self, _id: &CPID, args: &[&str]) {
-        if args.len() != 0 {
+        if !args.is_empty() {
             println!("Warning: invalid message _print:{}. _print \
                       takes no arguments", args.join(":"));
             return;
@@ -620,6 +1091,172 @@ impl CPServer {
 
 }
 
+//================================== Waker ===================================//
+// The socket games poke (IpcBackend::notify_server) after writing to an IPC object
+// so that the main loop wakes up right away instead of at the next timeout
+struct Waker {
+    #[cfg(unix)]
+    sock: Option<mio::net::UnixDatagram>,
+}
+
+impl Waker {
+    #[cfg(unix)]
+    fn new(registry: &Registry) -> Self {
+        // unwrap because main() already checked the directories
+        let path = ipc::wake_path().unwrap();
+        // a socket file left over from a previous run would make bind fail
+        let _ = std::fs::remove_file(&path);
+        let sock = match mio::net::UnixDatagram::bind(&path) {
+            Ok(mut sock) => {
+                registry.register(&mut sock, WAKE_TOKEN, Interest::READABLE)
+                    .unwrap_or_else(|e| {
+                        println!("Warning: failed to register {}: {}", path, e);
+                    });
+                Some(sock)
+            }
+            Err(e) => {
+                println!("Warning: failed to bind {}: {}. Messages from games \
+                          will be checked every {:?}", path, e, IDLE_TIMEOUT);
+                None
+            }
+        };
+        Waker { sock }
+    }
+
+    #[cfg(not(unix))]
+    fn new(_registry: &Registry) -> Self {
+        Waker {}
+    }
+
+    // throw away the wakeups we've received so the socket doesn't fill up
+    fn drain(&self) {
+        #[cfg(unix)]
+        if let Some(sock) = &self.sock {
+            let mut buf = [0_u8; 64];
+            while sock.recv(&mut buf).is_ok() {}
+        }
+    }
+}
+
+
+//================================= Arguments ================================//
+const USAGE: &str = "\
+usage: server [--port PORT] [--www DIR] [--ipc-dir DIR] [--lock-dir DIR]
+              [--namespace NAME]
+              [--tls-cert FILE --tls-key FILE | --tls-self-signed NAMES]
+
+  --port PORT       port phones connect to (default 50079)
+  --www DIR         serve the controller page and its files from DIR over
+                    plain HTTP(S) on the same port
+  --ipc-dir DIR     where IPC objects are kept (env CONTROLPAD_IPC_DIR)
+  --lock-dir DIR    where lock files are kept (env CONTROLPAD_LOCK_DIR)
+  --namespace NAME  keep this server's files apart from other servers using
+                    the same directories (env CONTROLPAD_NAMESPACE)
+  --tls-cert FILE   serve wss:// with the PEM certificate chain in FILE...
+  --tls-key FILE    ...and the PEM private key in FILE
+  --tls-self-signed NAMES
+                    serve wss:// with a certificate generated at startup for
+                    NAMES, a comma separated list of the host names and IP
+                    addresses phones connect to
+
+Games must be run with the same CONTROLPAD_* environment to reach this server.";
+
+const DEFAULT_PORT: &str = "50079";
+
+struct Args {
+    port: String,
+    dirs: config::Dirs,
+    // empty when not given
+    www: String,
+    tls_cert: String,
+    tls_key: String,
+    tls_self_signed: String,
+}
+
+// start from the environment and let the command line override it
+fn parse_args() -> Args {
+    let mut parsed = Args {
+        port: DEFAULT_PORT.to_string(),
+        dirs: config::Dirs::from_env(),
+        www: String::new(),
+        tls_cert: String::new(),
+        tls_key: String::new(),
+        tls_self_signed: String::new(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--port" => &mut parsed.port,
+            "--www" => &mut parsed.www,
+            "--ipc-dir" => &mut parsed.dirs.ipc_dir,
+            "--lock-dir" => &mut parsed.dirs.lock_dir,
+            "--namespace" => &mut parsed.dirs.namespace,
+            "--tls-cert" => &mut parsed.tls_cert,
+            "--tls-key" => &mut parsed.tls_key,
+            "--tls-self-signed" => &mut parsed.tls_self_signed,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => {
+                println!("ERROR: unrecognized argument '{}'\n{}", arg, USAGE);
+                std::process::exit(1);
+            }
+        };
+        match args.next() {
+            Some(value) => *field = value,
+            None => {
+                println!("ERROR: {} needs a value\n{}", arg, USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+    parsed
+}
+
+impl Args {
+    fn wants_tls(&self) -> bool {
+        !self.tls_cert.is_empty() || !self.tls_key.is_empty() ||
+            !self.tls_self_signed.is_empty()
+    }
+}
+
+// the TLS configuration asked for on the command line, if any
+#[cfg(feature = "tls")]
+fn tls_config(args: &Args) -> Result<Option<Arc<rustls::ServerConfig>>> {
+    if !args.wants_tls() {
+        return Ok(None);
+    }
+    let config = if !args.tls_self_signed.is_empty() {
+        if !args.tls_cert.is_empty() || !args.tls_key.is_empty() {
+            return Err("--tls-self-signed can't be used with --tls-cert or \
+                        --tls-key".into());
+        }
+        let names: Vec<String> = args.tls_self_signed.split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
+        tls::self_signed_config(&names).map_err(|e| e.to_string())?
+    } else if args.tls_cert.is_empty() || args.tls_key.is_empty() {
+        return Err("--tls-cert and --tls-key must be given together".into());
+    } else {
+        tls::config_from_files(&args.tls_cert, &args.tls_key).map_err(|e| e.to_string())?
+    };
+    Ok(Some(config))
+}
+
+// a number of milliseconds from the environment variable var
+fn env_ms(var: &str, default: u64) -> u64 {
+    match std::env::var(var) {
+        Ok(s) => s.parse::<u64>().unwrap_or_else(|e| {
+            println!("Warning: invalid {} '{}': {}", var, s, e);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+
 //=================================== main ===================================//
 fn main() {
 
@@ -631,22 +1268,508 @@ fn main() {
         }
     }
     // TODO: do we need to do an admin check for Windows?^^^
+
+    let args = parse_args();
+    #[cfg(feature = "tls")]
+    let tls_config = tls_config(&args).unwrap_or_else(|e| {
+        println!("ERROR: {}", e);
+        std::process::exit(1);
+    });
+    #[cfg(not(feature = "tls"))]
+    if args.wants_tls() {
+        println!("ERROR: this server was built without TLS support (build it \
+                  with --features tls)");
+        std::process::exit(1);
+    }
+    if !args.www.is_empty() && !std::path::Path::new(&args.www).is_dir() {
+        println!("ERROR: {} is not a directory", args.www);
+        std::process::exit(1);
+    }
+    config::set_dirs(args.dirs).unwrap_or_else(|e| {
+        println!("ERROR: {}", e);
+        std::process::exit(1);
+    });
     
     // create expected directories for various modules
+    systemlock::initialize().unwrap_or_else(|e| {
+        println!("ERROR: {}", e);
+        std::process::exit(1);
+    });
+    // make sure we're the only server in our namespace, then get rid of
+    // anything a previous server left behind
+    let instance = systemlock::InstanceLock::acquire().unwrap_or_else(|e| {
+        println!("ERROR: {}", e);
+        std::process::exit(1);
+    });
+    ipc::purge().unwrap_or_else(|e| {
+        println!("Warning: failed to delete old IPC objects: {}", e);
+    });
     ipc::initialize();
-    systemlock::initialize();
+    let ipc_backend = ipc::server_backend()
+        .unwrap_or_else(|e| panic!("Fatal Error: Could not host IPC: {}", e));
     
+    let grace_ms = env_ms("CONTROLPAD_GRACE_MS", DEFAULT_GRACE_PERIOD_MS);
+    let timeout_ms = env_ms("CONTROLPAD_TIMEOUT_MS", DEFAULT_SILENCE_TIMEOUT_MS);
+    let suspend_notices = std::env::var("CONTROLPAD_SUSPEND_NOTICES").is_ok_and(|v| v == "1");
+
     // start server
-    let mut cpserver = CPServer::new("50079");
-    loop {
-        cpserver.accept_new_sawkets();
-        cpserver.handle_subids();
-        cpserver.handle_messages_from_target();
-        cpserver.handle_messages_from_clients();
-        cpserver.clear_dead_clients();
-        cpserver.send_reloads_to_clients();
-        std::thread::sleep(std::time::Duration::from_micros(1500));
+    let mut poll = Poll::new()
+        .unwrap_or_else(|e| panic!("Fatal Error: Could not create poll: {}", e));
+    let mut events = Events::with_capacity(256);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, shutdown.clone())
+            .unwrap_or_else(|e| panic!("Fatal Error: Could not handle signal: {}", e));
+    }
+    let waker = Waker::new(poll.registry());
+    let mut cpserver = CPServer::new(&args.port, Duration::from_millis(grace_ms),
+                                     Duration::from_millis(timeout_ms),
+                                     poll.registry(), ipc_backend);
+    cpserver.suspend_notices = suspend_notices;
+    if !args.www.is_empty() {
+        cpserver.server.serve_files_from("/", Some(args.www.into()));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls_config {
+        cpserver.server.set_tls(config);
+        println!("Note: phones must connect with wss://");
     }
+    while !shutdown.load(Ordering::SeqCst) {
+        if let Err(e) = poll.poll(&mut events, Some(IDLE_TIMEOUT)) {
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                println!("Warning: poll failed: {}", e);
+            }
+        }
+        waker.drain();
+        cpserver.update();
+    }
+
+    // clean up after ourselves so games don't see phantom clients
+    drop(cpserver);
+    ipc::purge().unwrap_or_else(|e| {
+        println!("Warning: failed to delete IPC objects: {}", e);
+    });
+    instance.release().unwrap_or_else(|e| {
+        println!("Warning: failed to release instance lock: {}", e);
+    });
+    println!("controlpad server stopped");
 }
 
+//=================================== Tests ==================================//
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controlpads::ipc::MemoryBackend;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+    use tungstenite::Message;
+
+    // the controlpads library has one backend per process so tests that use
+    // it take turns
+    static LIBRARY: Mutex<()> = Mutex::new(());
+
+    const SILENCE_TIMEOUT: Duration = Duration::from_millis(DEFAULT_SILENCE_TIMEOUT_MS);
+
+    // the next message a browser would see (tungstenite hands us pings, and
+    // answers them for us), None once the connection is gone
+    fn next_message(ws: &mut tungstenite::WebSocket<TcpStream>) -> Option<Message> {
+        loop {
+            match ws.read_message().ok()? {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                msg => return Some(msg),
+            }
+        }
+    }
+
+    // connect a phone to the server on port and negotiate a session
+    fn connect_phone(port: u16) -> tungstenite::WebSocket<TcpStream> {
+        connect_phone_as(port, "").0
+    }
+
+    // connect a phone presenting token (from an earlier session, "" for a
+    // new one). Out: the websocket and the token the server handed back
+    fn connect_phone_as(port: u16, token: &str) -> (tungstenite::WebSocket<TcpStream>, String) {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let url = format!("ws://127.0.0.1:{}", port);
+        let (mut ws, _) = tungstenite::client(url, stream).unwrap();
+        let mut hello = vec![0];
+        hello.extend_from_slice(token.as_bytes());
+        ws.write_message(Message::Binary(hello)).unwrap();
+        let reply = next_message(&mut ws).unwrap().into_text().unwrap();
+        let token = reply.strip_prefix("_token:").unwrap_or_else(|| panic!("{}", reply));
+        (ws, token.to_string())
+    }
+
+    // a server on a free port sharing an in-memory backend with the
+    // controlpads library, which the caller has to itself until the guard
+    // is dropped
+    fn test_server(grace_period: Duration, silence_timeout: Duration)
+                   -> (std::sync::MutexGuard<'static, ()>, Arc<MemoryBackend>, Poll, CPServer) {
+        let library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+        let memory = Arc::new(MemoryBackend::new());
+        controlpads::set_backend(memory.clone());
+        let poll = Poll::new().unwrap();
+        let cpserver = CPServer::new("0", grace_period, silence_timeout, poll.registry(),
+                                     memory.clone());
+        (library, memory, poll, cpserver)
+    }
+
+    // keep updating the server until done() says so
+    fn update_until(cpserver: &mut CPServer, mut done: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            cpserver.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // a phone and a game talking through a server, all in this process with
+    // the IPC objects in memory
+    #[test]
+    fn relays_between_phone_and_game() {
+        let (_library, _memory, _poll, mut cpserver) = test_server(Duration::ZERO, SILENCE_TIMEOUT);
+        let port = cpserver.server.port();
+        let phone = std::thread::spawn(move || {
+            let mut ws = connect_phone(port);
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Text("hello phone".into()));
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Binary(vec![GAME_BYTES_HEADER, 0, 1]));
+            ws.write_message(Message::Text("hi game".into())).unwrap();
+            ws.write_message(Message::Binary(vec![GAME_BYTES_HEADER, 0x7f, 0])).unwrap();
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Text("hello everyone".into()));
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Binary(vec![GAME_BYTES_HEADER, 5]));
+            // stay connected until the server goes away
+            while next_message(&mut ws).is_some() {}
+        });
+        let waiter = std::thread::spawn(|| {
+            controlpads::wait_for_clients_changed(Duration::from_secs(5)).unwrap()
+        });
+        update_until(&mut cpserver, || waiter.is_finished());
+        assert!(waiter.join().unwrap());
+        let handles = controlpads::get_client_handles().unwrap();
+        assert_eq!(handles.len(), 1);
+        assert_eq!(handles[0].index(), 0);
+        assert_eq!(handles[0].state(), ClientState::Connected);
+        assert!(!handles[0].name().is_empty());
+        let stranger = ClientHandle::new("nobody-0", 99, "Nobody", SystemTime::now(),
+                                         ClientState::Connected);
+        assert!(matches!(controlpads::send_message(&stranger, "hi"),
+                         Err(controlpads::ControlpadError::ClientNotFound(_))));
+        controlpads::send_message(&handles[0], "hello phone").unwrap();
+        controlpads::send_bytes(&handles[0], &[0, 1]).unwrap();
+        let waiter = std::thread::spawn(|| {
+            controlpads::wait_for_messages(Duration::from_secs(5)).unwrap()
+        });
+        update_until(&mut cpserver, || waiter.is_finished());
+        assert!(waiter.join().unwrap());
+        let mut msgs = vec![];
+        update_until(&mut cpserver, || {
+            msgs.append(&mut controlpads::get_messages(&handles[0]).unwrap());
+            msgs.len() >= 2
+        });
+        assert_eq!(msgs, vec![controlpads::Message::Text("hi game".to_string()),
+                              controlpads::Message::Bytes(vec![0x7f, 0])]);
+        assert!(matches!(controlpads::send_to(&[handles[0].clone(), stranger], "hi"),
+                         Err(controlpads::ControlpadError::ClientNotFound(_))));
+        controlpads::broadcast("hello everyone").unwrap();
+        cpserver.update();
+        controlpads::send_bytes_to(&handles, &[5]).unwrap();
+        cpserver.update();
+        drop(cpserver);
+        phone.join().unwrap();
+    }
+
+    // the game hears about a phone joining, renaming itself, saying something
+    // and leaving as events in that order
+    #[test]
+    fn reports_client_events() {
+        let (_library, _memory, _poll, mut cpserver) = test_server(Duration::ZERO, SILENCE_TIMEOUT);
+        let port = cpserver.server.port();
+        // subscribe before anyone shows up
+        assert!(controlpads::poll_events().unwrap().is_empty());
+        cpserver.update();
+        let phone = std::thread::spawn(move || {
+            let mut ws = connect_phone(port);
+            ws.write_message(Message::Text("_change_name:Zed".into())).unwrap();
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Text("_name:Zed".into()));
+            ws.write_message(Message::Text("hi game".into())).unwrap();
+            ws.close(None).unwrap();
+            while next_message(&mut ws).is_some() {}
+        });
+        let mut events = vec![];
+        update_until(&mut cpserver, || {
+            events.append(&mut controlpads::poll_events().unwrap());
+            matches!(events.last(), Some(Event::Left(_)))
+        });
+        phone.join().unwrap();
+        assert_eq!(events.len(), 4, "{:?}", events);
+        let Event::Joined(joined) = &events[0] else { panic!("{:?}", events[0]) };
+        let Event::Renamed { client, old_name } = &events[1] else { panic!("{:?}", events[1]) };
+        assert_eq!((client, client.name(), old_name), (joined, "Zed", &joined.name().to_string()));
+        let Event::Message(sender, msg, timing) = &events[2] else { panic!("{:?}", events[2]) };
+        assert_eq!((sender, msg), (client, &controlpads::Message::Text("hi game".into())));
+        assert!(timing.sent_at <= timing.received_at);
+        assert_eq!(events[3], Event::Left(client.clone()));
+    }
+
+    // a game that subscribes again (e.g. after restarting) is told about the
+    // clients that are already there again
+    #[test]
+    fn subscribing_again_repeats_joins() {
+        let (_library, memory, _poll, mut cpserver) = test_server(Duration::ZERO, SILENCE_TIMEOUT);
+        let port = cpserver.server.port();
+        let (done, phone_done) = std::sync::mpsc::channel::<()>();
+        let phone = std::thread::spawn(move || {
+            let mut ws = connect_phone(port);
+            phone_done.recv().unwrap();
+            ws.close(None).unwrap();
+            while next_message(&mut ws).is_some() {}
+        });
+        assert!(controlpads::poll_events().unwrap().is_empty());
+        let mut events = vec![];
+        update_until(&mut cpserver, || {
+            events.append(&mut controlpads::poll_events().unwrap());
+            !events.is_empty()
+        });
+        let Event::Joined(client) = &events[0] else { panic!("{:?}", events) };
+        let subscribe = controlpads::Message::Text(event::SUBSCRIBE.to_string());
+        memory.write("rpc_out", &frame::encode(&[subscribe])).unwrap();
+        let mut again = vec![];
+        update_until(&mut cpserver, || {
+            again.append(&mut controlpads::poll_events().unwrap());
+            !again.is_empty()
+        });
+        assert_eq!(again, vec![Event::Joined(client.clone())]);
+        done.send(()).unwrap();
+        update_until(&mut cpserver, || phone.is_finished());
+        phone.join().unwrap();
+    }
+
+    // the game's controller is served from the server's port and clients are
+    // told to switch to it, and back to the default page when it's withdrawn
+    #[test]
+    fn swaps_controllers() {
+        let (_library, _memory, _poll, mut cpserver) = test_server(Duration::ZERO, SILENCE_TIMEOUT);
+        let port = cpserver.server.port();
+        let bundle = tempfile::tempdir().unwrap();
+        std::fs::write(bundle.path().join("index.html"), "<p>racing</p>").unwrap();
+        let phone = std::thread::spawn(move || {
+            let mut ws = connect_phone(port);
+            let url = next_message(&mut ws).unwrap().into_text().unwrap();
+            let url = url.strip_prefix("_controller:").unwrap().to_string();
+            assert_eq!(url, "/controller/1/");
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: pad\r\n\r\n", url).unwrap();
+            let mut page = String::new();
+            stream.read_to_string(&mut page).unwrap();
+            assert!(page.starts_with("HTTP/1.1 200 OK"), "{}", page);
+            assert!(page.ends_with("<p>racing</p>"), "{}", page);
+            ws.write_message(Message::Text("loaded".into())).unwrap();
+            assert_eq!(next_message(&mut ws).unwrap(), Message::Text("_controller:".into()));
+            while next_message(&mut ws).is_some() {}
+        });
+        update_until(&mut cpserver, || controlpads::clients_changed().unwrap());
+        let handles = controlpads::get_client_handles().unwrap();
+        controlpads::set_controller(Some(bundle.path().to_str().unwrap())).unwrap();
+        update_until(&mut cpserver, || {
+            !controlpads::get_messages(&handles[0]).unwrap().is_empty()
+        });
+        controlpads::set_controller(None).unwrap();
+        cpserver.update();
+        drop(cpserver);
+        phone.join().unwrap();
+    }
+
+    // a phone that answers pings gets a latency and one that goes quiet (like
+    // a phone that lost its Wi-Fi) is dropped without its socket closing
+    #[test]
+    fn measures_latency_and_expires_silent_phones() {
+        let (_library, _memory, _poll, mut cpserver) =
+            test_server(Duration::ZERO, Duration::from_millis(300));
+        let port = cpserver.server.port();
+        let (answer, phone_answer) = std::sync::mpsc::channel::<()>();
+        let (expired, phone_expired) = std::sync::mpsc::channel::<()>();
+        let phone = std::thread::spawn(move || {
+            let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let (mut ws, _) = tungstenite::client(format!("ws://127.0.0.1:{}", port), stream)
+                .unwrap();
+            // ask for a new session without reading the reply, since reading
+            // is what answers pings
+            ws.write_message(Message::Binary(vec![0])).unwrap();
+            phone_answer.recv().unwrap();
+            while next_message(&mut ws).unwrap() != Message::Text("go quiet".into()) {}
+            // keep the socket open until the server has given up on us
+            let _ = phone_expired.recv();
+        });
+        update_until(&mut cpserver, || controlpads::clients_changed().unwrap());
+        let handles = controlpads::get_client_handles().unwrap();
+        // pinged (every 100ms) but not answered
+        let unanswered = Instant::now();
+        while unanswered.elapsed() < Duration::from_millis(150) {
+            cpserver.update();
+            assert_eq!(controlpads::latency(&handles[0]).unwrap(), None);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        answer.send(()).unwrap();
+        update_until(&mut cpserver, || controlpads::latency(&handles[0]).unwrap().is_some());
+        assert!(controlpads::latency(&handles[0]).unwrap().unwrap() < Duration::from_millis(300));
+        controlpads::send_message(&handles[0], "go quiet").unwrap();
+        let quiet = Instant::now();
+        update_until(&mut cpserver, || {
+            controlpads::clients_changed().unwrap() &&
+                controlpads::get_client_handles().unwrap().is_empty()
+        });
+        assert!(quiet.elapsed() >= Duration::from_millis(200), "{:?}", quiet.elapsed());
+        assert!(!phone.is_finished());
+        expired.send(()).unwrap();
+        drop(cpserver);
+        phone.join().unwrap();
+    }
+
+    // SystemApps that predate framing still get through
+    #[test]
+    fn reads_framed_and_legacy_rpc() {
+        let framed = frame::encode(&[controlpads::Message::Text("reload".into()),
+                                     controlpads::Message::Text(event::SUBSCRIBE.into())]);
+        assert_eq!(decode_rpc(&framed).unwrap(), vec!["reload", event::SUBSCRIBE]);
+        assert_eq!(decode_rpc(b"reload\0set_controller:\0").unwrap(),
+                   vec!["reload", "set_controller:"]);
+        assert!(decode_rpc(b"").unwrap().is_empty());
+    }
+
+    // phones that leave or go quiet before negotiating a token don't stay
+    // pending forever
+    #[test]
+    fn drops_dead_pending_sawkets() {
+        let silence_timeout = Duration::from_millis(300);
+        let (_library, _memory, _poll, mut cpserver) = test_server(Duration::ZERO, silence_timeout);
+        let port = cpserver.server.port();
+        let (close, phone_close) = std::sync::mpsc::channel::<()>();
+        let (done, phones_done) = std::sync::mpsc::channel::<()>();
+        let phones = std::thread::spawn(move || {
+            let connect = || {
+                let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                tungstenite::client(format!("ws://127.0.0.1:{}", port), stream).unwrap().0
+            };
+            let mut leaving = connect();
+            // never reads, so never answers a ping
+            let _quiet = connect();
+            phone_close.recv().unwrap();
+            leaving.close(None).unwrap();
+            phones_done.recv().unwrap();
+        });
+        let start = Instant::now();
+        update_until_pending(&mut cpserver, 2);
+        close.send(()).unwrap();
+        update_until_pending(&mut cpserver, 1);
+        update_until_pending(&mut cpserver, 0);
+        assert!(start.elapsed() >= silence_timeout, "{:?}", start.elapsed());
+        assert!(cpserver.clients.is_empty());
+        done.send(()).unwrap();
+        phones.join().unwrap();
+    }
+
+    // keep updating the server until n phones are waiting to negotiate
+    fn update_until_pending(cpserver: &mut CPServer, n: usize) {
+        let start = Instant::now();
+        while cpserver.pending_sawkets.len() != n {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            cpserver.update();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    // a phone that drops off is suspended rather than removed, what the game
+    // sends it meanwhile is held and it gets all of it, in order, when it
+    // comes back with its token
+    #[test]
+    fn replays_backlog_when_a_suspended_phone_returns() {
+        let (_library, _memory, _poll, mut cpserver) =
+            test_server(Duration::from_secs(30), SILENCE_TIMEOUT);
+        cpserver.suspend_notices = true;
+        let port = cpserver.server.port();
+        let (backlogged, phone_backlogged) = std::sync::mpsc::channel::<()>();
+        let phone = std::thread::spawn(move || {
+            let (mut ws, token) = connect_phone_as(port, "");
+            ws.close(None).unwrap();
+            while next_message(&mut ws).is_some() {}
+            phone_backlogged.recv().unwrap();
+            let (mut ws, returned) = connect_phone_as(port, &token);
+            assert_eq!(returned, token);
+            let msgs: Vec<Message> = (0..3).map(|_| next_message(&mut ws).unwrap()).collect();
+            ws.close(None).unwrap();
+            while next_message(&mut ws).is_some() {}
+            msgs
+        });
+        let mut handles = vec![];
+        update_until(&mut cpserver, || {
+            if controlpads::clients_changed().unwrap() {
+                handles = controlpads::get_client_handles().unwrap();
+            }
+            handles.first().is_some_and(|c| c.state() == ClientState::Suspended)
+        });
+        let client = handles[0].clone();
+        assert_eq!(controlpads::get_messages(&client).unwrap(),
+                   vec![controlpads::Message::Text("_suspended".into())]);
+        controlpads::send_message(&client, "one").unwrap();
+        controlpads::send_bytes(&client, &[2]).unwrap();
+        controlpads::send_message(&client, "three").unwrap();
+        cpserver.update();
+        assert_eq!(cpserver.clients[0].backlog.len(), 3);
+        backlogged.send(()).unwrap();
+        update_until(&mut cpserver, || phone.is_finished());
+        assert_eq!(phone.join().unwrap(), vec![Message::Text("one".into()),
+                                               Message::Binary(vec![GAME_BYTES_HEADER, 2]),
+                                               Message::Text("three".into())]);
+        assert_eq!(controlpads::get_messages(&client).unwrap(),
+                   vec![controlpads::Message::Text("_resumed".into()),
+                        controlpads::Message::Text("_suspended".into())]);
+        assert_eq!(controlpads::get_client_handles().unwrap().len(), 1);
+    }
+
+    // a phone whose clock is a minute fast syncs with the server and stamps a
+    // tap from half a second ago, and the game gets when that was by its clock
+    #[test]
+    fn times_messages_by_the_phones_clock() {
+        let (_library, _memory, _poll, mut cpserver) =
+            test_server(Duration::ZERO, Duration::from_millis(600));
+        let port = cpserver.server.port();
+        let phone = std::thread::spawn(move || {
+            let phone_now = || clock::to_millis(SystemTime::now()) + 60_000.0;
+            let mut ws = connect_phone(port);
+            ws.write_message(Message::Text(format!("_ping:{}", phone_now()))).unwrap();
+            let Some(Message::Text(pong)) = next_message(&mut ws) else { panic!() };
+            assert_eq!(pong.split(':').count(), 4, "{}", pong);
+            // now that we've shown we take part the server measures our clock
+            loop {
+                let Some(Message::Text(t)) = next_message(&mut ws) else { panic!() };
+                if let Some(t0) = t.strip_prefix("_ping:") {
+                    let pong = format!("_pong:{}:{}:{}", t0, phone_now(), phone_now());
+                    ws.write_message(Message::Text(pong)).unwrap();
+                    break;
+                }
+            }
+            let tapped = SystemTime::now() - Duration::from_millis(500);
+            ws.write_message(Message::Text(format!("_at:{}", phone_now() - 500.0))).unwrap();
+            ws.write_message(Message::Text("tap".into())).unwrap();
+            while next_message(&mut ws).is_some() {}
+            tapped
+        });
+        update_until(&mut cpserver, || controlpads::clients_changed().unwrap());
+        let handles = controlpads::get_client_handles().unwrap();
+        let mut msgs = vec![];
+        update_until(&mut cpserver, || {
+            msgs.append(&mut controlpads::get_timed_messages(&handles[0]).unwrap());
+            !msgs.is_empty()
+        });
+        drop(cpserver);
+        let tapped = phone.join().unwrap();
+        let (msg, timing) = &msgs[0];
+        assert_eq!(msg, &controlpads::Message::Text("tap".into()));
+        let error = timing.sent_at.duration_since(tapped).unwrap_or_else(|e| e.duration());
+        assert!(error < Duration::from_millis(100), "off by {:?}", error);
+        assert!(timing.transit() >= Duration::from_millis(400), "{:?}", timing);
+    }
+}
 //==================================<===|===>=================================//
@@ -0,0 +1,107 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>===================================
+use std::time::Duration;
+use crate::frame::{self, Message};
+use crate::ipc::Result;
+
+//=================================== Notes ====================================
+/*
+The server pings every client's websockets and writes what it measures to the
+STATS_OBJECT IPC object (rewritten whenever a measurement changes, without
+touching cp_clients so that clients_changed() keeps meaning what it says).
+Each client with a measurement is a Bytes frame (see frame.rs) holding:
+
+[id len: u16 LE][id][round trip time in microseconds: u32 LE]
+*/
+
+//================================= Constants ==================================
+pub const STATS_OBJECT: &str = "cp_stats";
+
+//=================================== Stats ====================================
+/* The stats object for clients' (id, round trip time) pairs.
+ */
+pub fn encode(rtts: &[(&str, Duration)]) -> Vec<u8> {
+    let msgs: Vec<Message> = rtts.iter().map(|(id, rtt)| {
+        let micros = u32::try_from(rtt.as_micros()).unwrap_or(u32::MAX);
+        let mut payload = Vec::with_capacity(2 + id.len() + 4);
+        payload.extend_from_slice(&(id.len() as u16).to_le_bytes());
+        payload.extend_from_slice(id.as_bytes());
+        payload.extend_from_slice(&micros.to_le_bytes());
+        Message::Bytes(payload)
+    }).collect();
+    frame::encode(&msgs)
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<(String, Duration)>> {
+    let mut ret = Vec::new();
+    for msg in frame::decode(data)? {
+        let Message::Bytes(payload) = msg else { continue };
+        let truncated = || format!("stats entry of {} bytes is truncated", payload.len());
+        let len_bytes = payload.get(..2).ok_or_else(truncated)?;
+        let len = u16::from_le_bytes([len_bytes[0], len_bytes[1]]) as usize;
+        let id = payload.get(2..2 + len).ok_or_else(truncated)?;
+        let micros = payload.get(2 + len..2 + len + 4).ok_or_else(truncated)?;
+        let micros = u32::from_le_bytes([micros[0], micros[1], micros[2], micros[3]]);
+        ret.push((String::from_utf8(id.to_vec())?, Duration::from_micros(micros as u64)));
+    }
+    Ok(ret)
+}
+
+//=================================== Tests ====================================
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(len: u16, id: &[u8], micros: &[u8]) -> Vec<u8> {
+        let mut payload = len.to_le_bytes().to_vec();
+        payload.extend_from_slice(id);
+        payload.extend_from_slice(micros);
+        frame::encode(&[Message::Bytes(payload)])
+    }
+
+    #[test]
+    fn round_trips_round_trip_times() {
+        let rtts = [("0a9574c70a06d95f-3", Duration::from_micros(1_234)),
+                    ("", Duration::ZERO),
+                    ("Ünïcode", Duration::from_millis(250))];
+        assert_eq!(decode(&encode(&rtts)).unwrap(),
+                   rtts.iter().map(|(id, rtt)| (id.to_string(), *rtt)).collect::<Vec<_>>());
+        assert!(decode(&encode(&[])).unwrap().is_empty());
+        // too long to count in microseconds
+        let slow = decode(&encode(&[("slow", Duration::from_secs(1 << 40))])).unwrap();
+        assert_eq!(slow, vec![("slow".to_string(), Duration::from_micros(u32::MAX as u64))]);
+    }
+
+    #[test]
+    fn rejects_truncated_entries() {
+        let micros = 1_234u32.to_le_bytes();
+        assert!(decode(&entry(2, b"ab", &micros)).is_ok());
+        // an id length beyond the payload
+        assert!(decode(&entry(40, b"ab", &micros)).is_err());
+        // a round trip time cut short
+        assert!(decode(&entry(2, b"ab", &micros[..3])).is_err());
+        assert!(decode(&entry(2, b"ab", &[])).is_err());
+        frame::tests::assert_rejects_truncations(
+            &encode(&[("ab", Duration::from_micros(1_234))]), decode);
+    }
+}
+
+//==================================<===|===>===================================
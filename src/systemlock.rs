@@ -21,7 +21,9 @@
 extern crate fs2;
 use fs2::FileExt;
 use std::fs::{OpenOptions, File};
+use std::io::{Read, Write};
 use std::error::Error;
+use crate::config;
 
 //=================================== Notes ====================================
 /* 
@@ -33,39 +35,21 @@ When I tried to implement ipc using base FileLock I got race conditions (that
 were fixed by wrapping that same code in one of these systemlocks)
 */
 
-//================================= Constants ==================================
-#[cfg(target_os = "macos")]
-const LOCK_DIR: &str = "/var/tmp";
-#[cfg(target_os = "linux")]
-//const LOCK_DIR: &str = "/var/lock/";
-const LOCK_DIR: &str = "/dev/shm/";
-#[cfg(target_os = "windows")]
-const LOCK_DIR: &str = "C:\\Users\\gamenite";
-//
-#[cfg(target_os = "macos")]
-const LOCK_PREFIX: &str = "/sl_";
-#[cfg(target_os = "linux")]
-const LOCK_PREFIX: &str = "/sl_";
-#[cfg(target_os = "windows")]
-const LOCK_PREFIX: &str = "\\sl_";
-    
 //================================== Helpers ===================================
 #[allow(dead_code)]
-pub fn initialize() {
+pub fn initialize() -> Result<(), Box<dyn Error + Send + Sync>> {
     //#[cfg(debug_assertions)] println!("locked initialize");
-    if !std::path::Path::new(LOCK_DIR).exists() {
-	std::fs::create_dir(LOCK_DIR)
-            .unwrap_or_else(|e| {
-                let help_msg = format!(
-                    "Try creating {} yourself and giving yourself permission to \
-                     make files within that directory",
-                    LOCK_DIR
-                );
-                panic!("Fatal Error: Could not create {}: {}\n{}",
-                       LOCK_DIR, e, help_msg);
-            });
+    let lock_dir = &config::dirs()?.lock_dir;
+    if !std::path::Path::new(lock_dir).exists() {
+	std::fs::create_dir_all(lock_dir).map_err(|e| {
+            format!("Could not create {}: {}\nTry creating {} yourself and \
+                     giving yourself permission to make files within that \
+                     directory", lock_dir, e, lock_dir)
+        })?;
     }
-    // TODO: delete previous Lock data in the dir
+    // lock files left behind by a previous server are deleted by ipc::purge
+    // along with the objects they guard
+    Ok(())
 }
 
 //================================== Locked ====================================
@@ -74,13 +58,14 @@ pub struct Locked {
     lock: File,
 }
 impl Locked {
-    pub fn new(s: &str) -> Result<Self, Box<dyn Error>> {
-        let path = String::from(LOCK_DIR) + LOCK_PREFIX + s;
-        let file = OpenOptions::new().read(true).write(true).create(true).open(&path)
-            .unwrap_or_else(|e| {
-                panic!("Fatal Error: Failed to open {}: {}\n(Try changing \
-                        permissions on {})", &path, e, LOCK_DIR);
-            });
+    pub fn new(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let dirs = config::dirs()?;
+        let path = dirs.lock_path(s);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+            .map_err(|e| {
+                format!("Failed to open {}: {} (try changing permissions on {})",
+                        &path, e, dirs.lock_dir)
+            })?;
         file.lock_exclusive()?;
         Ok(Locked { lock: file })
     }
@@ -91,4 +76,55 @@ impl Locked {
 
 }
 
+//=============================== InstanceLock =================================
+// Held by a running server for its whole life (the lock file holds its pid) so
+// that a second server in the same namespace, and games, can tell that it's
+// there.
+const INSTANCE_LOCK_NAME: &str = "server";
+
+pub struct InstanceLock {
+    lock: File,
+    path: String,
+}
+
+impl InstanceLock {
+    pub fn acquire() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let dirs = config::dirs()?;
+        let path = dirs.lock_path(INSTANCE_LOCK_NAME);
+        let mut file = OpenOptions::new().read(true).write(true).create(true)
+            .truncate(false).open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(format!("another controlpad server (pid {}) is already \
+                                using {}", pid.trim(),
+                               dirs.ipc_path()).into());
+        }
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
+        Ok(InstanceLock { lock: file, path })
+    }
+
+    pub fn release(self) -> Result<(), std::io::Error> {
+        std::fs::remove_file(&self.path)?;
+        self.lock.unlock()
+    }
+}
+
+/* True if a server is holding the instance lock for our namespace.
+ */
+pub fn server_running() -> bool {
+    let Ok(dirs) = config::dirs() else { return false };
+    let path = dirs.lock_path(INSTANCE_LOCK_NAME);
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    if file.try_lock_shared().is_ok() {
+        let _ = file.unlock();
+        return false;
+    }
+    true
+}
+
 //==================================<===|===>===================================
@@ -0,0 +1,67 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//==================================<===|===>=================================//
+use std::sync::Arc;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::pki_types::pem::PemObject;
+use crate::util::Result;
+
+//=================================== Notes ==================================//
+/*
+Browsers only give pages from a secure context the features controllers want
+(motion sensors, vibration, fullscreen on iOS), so the server can terminate
+TLS itself (built with the "tls" feature). It uses a certificate and key from
+PEM files, or a self-signed certificate generated at startup for the names
+and addresses phones will connect to. Phones have to accept a self-signed
+certificate by hand, and again every time the server restarts.
+*/
+
+//================================== Config ==================================//
+pub fn server_config(certs: Vec<CertificateDer<'static>>,
+                 key: PrivateKeyDer<'static>) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+// the certificate chain in cert_path and the private key in key_path
+pub fn config_from_files(cert_path: &str, key_path: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| format!("failed to read certificates from {}: {}", cert_path, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert_path).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| format!("failed to read a private key from {}: {}", key_path, e))?;
+    server_config(certs, key)
+}
+
+// a new self-signed certificate for names (host names or IP addresses)
+pub fn self_signed_config(names: &[String]) -> Result<Arc<ServerConfig>> {
+    let certified = rcgen::generate_simple_self_signed(names.to_vec())?;
+    let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+    server_config(vec![certified.cert.der().clone()], key.into())
+}
+//==================================<===|===>=================================//
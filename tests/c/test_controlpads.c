@@ -0,0 +1,155 @@
+/*
+ * Copyright 2022-2024 RecBox, Inc.
+ *
+ * This file is part of the ControlpadServer program of the GameNite project.
+ *
+ * ControlpadServer is free software: you can redistribute it and/or modify it 
+ * under the terms of the GNU General Public License as published by the Free 
+ * Software Foundation, either version 3 of the License, or (at your option) 
+ * any later version.
+ * 
+ * ControlpadServer is distributed in the hope that it will be useful, but 
+ * WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+ * or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+ * more details.
+ * 
+ * You should have received a copy of the GNU General Public License along with 
+ * ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Exercises the C API against a running controlpad server. Start the server,
+// connect a phone, then run this (see the Makefile). It waits for a client,
+// sends it a text and a binary message, and prints whatever the client sends
+// back until it has seen one message or the timeout runs out.
+
+#include <stdio.h>
+#include <string.h>
+#include <time.h>
+
+#include "controlpads.h"
+
+#define TIMEOUT_S 30
+
+static int failures = 0;
+
+#define CHECK(call) check((call), #call)
+
+static int check(int code, const char *what) {
+    if (code != CP_OK) {
+        fprintf(stderr, "FAIL: %s: %s (%d)\n", what, cp_error_string(code), code);
+        failures++;
+    }
+    return code;
+}
+
+static double now(void) {
+    struct timespec ts;
+    clock_gettime(CLOCK_MONOTONIC, &ts);
+    return ts.tv_sec + ts.tv_nsec / 1e9;
+}
+
+static void sleep_ms(long ms) {
+    struct timespec ts = { ms / 1000, (ms % 1000) * 1000000 };
+    nanosleep(&ts, NULL);
+}
+
+static void print_message(const CPClient *client, const CPMessage *msg) {
+    if (msg->kind == CP_MESSAGE_KIND_TEXT) {
+        printf("%s: text '%.*s'\n", cp_client_name(client), (int)msg->len,
+               (const char *)msg->data);
+    } else {
+        printf("%s: %zu bytes:", cp_client_name(client), msg->len);
+        for (size_t i = 0; i < msg->len; i++) {
+            printf(" %02x", msg->data[i]);
+        }
+        printf("\n");
+    }
+    printf("  (took %llu us to arrive)\n",
+           (unsigned long long)(msg->received_us - msg->sent_us));
+}
+
+int main(void) {
+    double deadline = now() + TIMEOUT_S;
+
+    // wait for a client
+    CPClientList *clients = NULL;
+    while (now() < deadline) {
+        int changed = 0;
+        if (CHECK(cp_clients_changed(&changed)) != CP_OK) {
+            return 1;
+        }
+        if (changed) {
+            cp_client_list_free(clients);
+            clients = NULL;
+            CHECK(cp_get_client_handles(&clients));
+            if (cp_client_list_len(clients) > 0) {
+                break;
+            }
+        }
+        sleep_ms(10);
+    }
+    if (cp_client_list_len(clients) == 0) {
+        fprintf(stderr, "FAIL: no client connected within %d seconds\n", TIMEOUT_S);
+        cp_client_list_free(clients);
+        return 1;
+    }
+    if (cp_client_list_get(clients, cp_client_list_len(clients)) != NULL) {
+        fprintf(stderr, "FAIL: out of range cp_client_list_get wasn't NULL\n");
+        failures++;
+    }
+
+    // talk to it
+    // keep our own copy so the list can go away
+    CPClient *client = cp_client_clone(cp_client_list_get(clients, 0));
+    cp_client_list_free(clients);
+    printf("client %s (#%llu) connected at %llu ms, %s\n", cp_client_name(client),
+           (unsigned long long)cp_client_index(client),
+           (unsigned long long)cp_client_joined_ms(client),
+           cp_client_state(client) == CP_CLIENT_STATE_CONNECTED ? "connected" : "suspended");
+    int64_t latency = 0;
+    if (CHECK(cp_client_latency(client, &latency)) == CP_OK) {
+        printf("latency: %lld us\n", (long long)latency);
+    }
+    const uint8_t bytes[] = {0, 1, 2, 0xff};
+    CHECK(cp_send_message(client, "hello from C"));
+    CHECK(cp_send_bytes(client, bytes, sizeof bytes));
+    const CPClient *everyone[] = {client};
+    CHECK(cp_send_to(everyone, 1, "hello to everyone from C"));
+    if (cp_send_message(NULL, "hi") != CP_ERR_INVALID_ARGUMENT) {
+        fprintf(stderr, "FAIL: sending to NULL didn't fail\n");
+        failures++;
+    }
+
+    // wait for it to say something
+    size_t received = 0;
+    while (received == 0 && now() < deadline) {
+        CPMessageList *msgs = NULL;
+        if (CHECK(cp_get_messages(client, &msgs)) != CP_OK) {
+            break;
+        }
+        for (size_t i = 0; i < cp_message_list_len(msgs); i++) {
+            CPMessage msg;
+            if (CHECK(cp_message_list_get(msgs, i, &msg)) == CP_OK) {
+                print_message(client, &msg);
+                received++;
+            }
+        }
+        cp_message_list_free(msgs);
+        sleep_ms(10);
+    }
+    if (received == 0) {
+        fprintf(stderr, "FAIL: no message from %s within %d seconds\n",
+                cp_client_name(client), TIMEOUT_S);
+        failures++;
+    }
+
+    cp_client_free(client);
+    cp_client_list_free(NULL);
+    cp_message_list_free(NULL);
+    if (failures) {
+        fprintf(stderr, "%d failure(s)\n", failures);
+        return 1;
+    }
+    printf("ok\n");
+    return 0;
+}
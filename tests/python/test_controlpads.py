@@ -0,0 +1,79 @@
+#
+# Copyright 2022-2024 RecBox, Inc.
+#
+# This file is part of the ControlpadServer program of the GameNite project.
+#
+# ControlpadServer is free software: you can redistribute it and/or modify it 
+# under the terms of the GNU General Public License as published by the Free 
+# Software Foundation, either version 3 of the License, or (at your option) 
+# any later version.
+# 
+# ControlpadServer is distributed in the hope that it will be useful, but 
+# WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY 
+# or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for 
+# more details.
+# 
+# You should have received a copy of the GNU General Public License along with 
+# ControlpadServer. If not, see <https://www.gnu.org/licenses/>.
+#
+
+# Exercises the Python module against a running controlpad server. Start the
+# server, connect a phone, then run this (see the Makefile). It waits for a
+# client, sends it a text and a binary message, and prints whatever the client
+# sends back until it has seen one message or the timeout runs out.
+
+import sys
+import time
+
+import controlpads
+
+TIMEOUT_S = 30
+
+
+def main():
+    deadline = time.monotonic() + TIMEOUT_S
+    failures = 0
+
+    # wait for a client
+    clients = []
+    while not clients and time.monotonic() < deadline:
+        if controlpads.wait_for_clients_changed(max(deadline - time.monotonic(), 0)):
+            clients = controlpads.get_client_handles()
+    if not clients:
+        print("FAIL: no client connected", file=sys.stderr)
+        return 1
+
+    # talk to it
+    client = clients[0]
+    print(f"client {client.name} (#{client.index}) connected at "
+          f"{client.joined_at:.3f}, {client.state}")
+    controlpads.send_message(client, "hello from Python")
+    controlpads.send_message(client, bytes([0, 1, 2, 0xff]))
+    try:
+        controlpads.send_message(client, 42)
+        print("FAIL: sending an int didn't fail", file=sys.stderr)
+        failures += 1
+    except TypeError:
+        pass
+
+    # wait for it to say something
+    received = 0
+    while received == 0 and time.monotonic() < deadline:
+        for msg in controlpads.get_messages(client):
+            if isinstance(msg, str):
+                print(f"{client.name}: text '{msg}'")
+            else:
+                print(f"{client.name}: {len(msg)} bytes: {msg.hex(' ')}")
+            received += 1
+        if received == 0:
+            controlpads.wait_for_messages(max(deadline - time.monotonic(), 0))
+    if received == 0:
+        print("FAIL: nothing received", file=sys.stderr)
+        failures += 1
+
+    print("ok" if failures == 0 else f"{failures} failure(s)")
+    return 1 if failures else 0
+
+
+if __name__ == "__main__":
+    sys.exit(main())